@@ -0,0 +1,19 @@
+create table authorization_codes
+(
+	code char(128) not null
+		constraint authorization_codes_pk
+			primary key,
+	client_id varchar not null,
+	user_id char(128) not null,
+	redirect_uri varchar not null,
+	scope bigint not null,
+	code_challenge varchar,
+	code_challenge_method varchar,
+	nonce varchar,
+	user_agent varchar,
+	auth_time bigint not null,
+	code_expire bigint not null,
+	amr varchar default 'pwd' not null
+);
+
+comment on table authorization_codes is 'Hashes of short-lived single-use authorization codes waiting to be exchanged for tokens';
@@ -0,0 +1,10 @@
+create table client_assertions
+(
+	client_id char(128) not null,
+	jti varchar not null,
+	expire bigint not null,
+	constraint client_assertions_pk
+		primary key (client_id, jti)
+);
+
+comment on table client_assertions is 'Identifiers of private_key_jwt client assertions that were already used, kept until the assertion expires';
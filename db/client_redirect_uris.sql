@@ -0,0 +1,10 @@
+create table client_redirect_uris
+(
+	client_id char(128) not null,
+	redirect_uri varchar not null,
+	any_loopback_port boolean default false not null,
+	constraint client_redirect_uris_pk
+		primary key (client_id, redirect_uri)
+);
+
+comment on table client_redirect_uris is 'Redirect uris registered for each client, loopback uris of native apps can be allowed on any port';
@@ -0,0 +1,19 @@
+create table client_secrets
+(
+	client_id char(128) not null,
+	secret_id char(32) not null,
+	secret_hash varchar not null,
+	expire bigint,
+	created bigint not null
+);
+
+comment on table client_secrets is 'Argon2 hashes of client secrets, a client can have two active secrets with independent expiry';
+
+create unique index client_secrets_secret_hash_uindex
+	on client_secrets (secret_hash);
+
+create unique index client_secrets_secret_id_uindex
+	on client_secrets (secret_id);
+
+create index client_secrets_client_id_index
+	on client_secrets (client_id);
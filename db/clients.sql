@@ -5,7 +5,15 @@ create table clients
 			primary key,
 	client_name varchar not null,
 	internal boolean default false not null,
-	client_secret char(128) not null
+	public boolean default false not null,
+	client_secret char(128),
+	allowed_scope bigint default 0 not null,
+	all_scopes_allowed boolean default false not null,
+	token_endpoint_auth_method varchar default 'client_secret_basic' not null,
+	jwks text,
+	require_verified_email boolean default false not null,
+	account_events_uri varchar,
+	id_token_signed_response_alg varchar
 );
 
 comment on table clients is 'Client information';
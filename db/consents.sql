@@ -0,0 +1,11 @@
+create table consents
+(
+	user_id char(128) not null,
+	client_id char(128) not null,
+	scope bigint not null,
+	granted bigint not null,
+	constraint consents_pk
+		primary key (user_id, client_id)
+);
+
+comment on table consents is 'Scopes each user approved for third-party clients, internal clients never ask for consent';
@@ -0,0 +1,14 @@
+create table password_resets
+(
+	token_hash char(128) not null
+		constraint password_resets_pk
+			primary key,
+	user_id char(128) not null,
+	expire bigint not null,
+	created bigint not null
+);
+
+comment on table password_resets is 'Hashes of unused password reset tokens, a token is deleted once it is used';
+
+create index password_resets_user_id_index
+	on password_resets (user_id);
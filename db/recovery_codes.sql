@@ -0,0 +1,10 @@
+create table recovery_codes
+(
+	user_id char(128) not null,
+	code_hash char(128) not null
+);
+
+comment on table recovery_codes is 'Hashes of unused two-factor recovery codes, a code is deleted once it is used';
+
+create index recovery_codes_user_id_index
+	on recovery_codes (user_id);
@@ -0,0 +1,10 @@
+create table rotated_refresh_tokens
+(
+	refresh_token char(128) not null
+		constraint rotated_refresh_tokens_pk
+			primary key,
+	family_id char(32) not null,
+	rotated bigint not null
+);
+
+comment on table rotated_refresh_tokens is 'Refresh tokens that were already exchanged, used to detect refresh token reuse';
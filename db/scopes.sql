@@ -0,0 +1,19 @@
+create table scopes
+(
+	name varchar not null
+		constraint scopes_pk
+			primary key,
+	bit bigint not null,
+	display_name varchar not null,
+	description varchar not null
+);
+
+comment on table scopes is 'Scopes clients can request, bit is a power of two unique to the scope so scopes can still be sent as a bitmask';
+
+create unique index scopes_bit_uindex
+	on scopes (bit);
+
+insert into scopes (name, bit, display_name, description) values ('email', 1, 'Email', 'Your email address');
+insert into scopes (name, bit, display_name, description) values ('profile', 2, 'Username', 'Your username');
+insert into scopes (name, bit, display_name, description) values ('openid', 4, 'Sign in', 'Signing you in with your account');
+insert into scopes (name, bit, display_name, description) values ('offline_access', 8, 'Offline access', 'Staying connected while you aren''t using the application');
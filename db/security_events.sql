@@ -0,0 +1,13 @@
+create table security_events
+(
+	user_id char(128) not null,
+	client_id varchar,
+	event varchar not null,
+	details varchar,
+	created bigint not null
+);
+
+comment on table security_events is 'Security relevant events such as detected refresh token reuse';
+
+create index security_events_user_id_index
+	on security_events (user_id);
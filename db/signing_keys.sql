@@ -0,0 +1,12 @@
+create table signing_keys
+(
+	kid char(32) not null
+		constraint signing_keys_pk
+			primary key,
+	algorithm varchar not null,
+	private_key bytea not null,
+	public_key bytea not null,
+	created bigint not null
+);
+
+comment on table signing_keys is 'Keys used to sign ID tokens, previous keys are kept so their tokens can still be verified';
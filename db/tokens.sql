@@ -4,8 +4,30 @@ create table tokens
 	user_id char(128) not null,
 	access_token char(128) not null,
 	access_token_expire bigint not null,
-	refresh_token char(128) not null
+	refresh_token char(128) not null,
+	refresh_token_expire bigint not null,
+	family_id char(32) not null,
+	family_expire bigint not null,
+	scope bigint not null,
+	access_token_revoked boolean default false not null,
+	refresh_token_revoked boolean default false not null,
+	user_agent varchar,
+	created bigint not null,
+	access_token_created bigint not null,
+	last_used bigint,
+	tokens_hashed boolean default true not null,
+	amr varchar default 'pwd' not null,
+	client_token boolean default false not null
 );
 
-comment on table tokens is 'Access and refresh tokens with expiration dates';
+comment on table tokens is 'Access and refresh tokens with expiration dates, one row for every grant so each device has its own set';
+
+create unique index tokens_family_id_uindex
+	on tokens (family_id);
+
+create unique index tokens_access_token_uindex
+	on tokens (access_token);
+
+create unique index tokens_refresh_token_uindex
+	on tokens (refresh_token);
 
@@ -0,0 +1,45 @@
+-- Brings a tokens table created before tokens were hashed at rest up to date with tokens.sql.
+-- Run it once before starting the new version, the server then hashes the existing tokens on startup
+-- and expands their scope 0 to email and profile.
+
+alter table tokens add column refresh_token_expire bigint;
+alter table tokens add column family_id char(32);
+alter table tokens add column family_expire bigint;
+alter table tokens add column scope bigint default 0 not null;
+alter table tokens add column access_token_revoked boolean default false not null;
+alter table tokens add column refresh_token_revoked boolean default false not null;
+alter table tokens add column user_agent varchar;
+alter table tokens add column created bigint;
+alter table tokens add column access_token_created bigint;
+alter table tokens add column last_used bigint;
+alter table tokens add column tokens_hashed boolean default false not null;
+alter table tokens add column amr varchar default 'pwd' not null;
+alter table tokens add column client_token boolean default false not null;
+
+-- Existing refresh tokens get the idle (60 days) and absolute (one year) lifetime from now on
+update tokens set
+	refresh_token_expire = extract(epoch from now())::bigint + 60*60*24*60,
+	family_id = md5(random()::text || clock_timestamp()::text || refresh_token),
+	family_expire = extract(epoch from now())::bigint + 60*60*24*365,
+	created = extract(epoch from now())::bigint,
+	access_token_created = extract(epoch from now())::bigint;
+
+alter table tokens alter column refresh_token_expire set not null;
+alter table tokens alter column family_id set not null;
+alter table tokens alter column family_expire set not null;
+alter table tokens alter column created set not null;
+alter table tokens alter column access_token_created set not null;
+
+-- Only the rows above are stored in plaintext, everything inserted from now on is already hashed
+alter table tokens alter column tokens_hashed set default true;
+
+comment on table tokens is 'Access and refresh tokens with expiration dates, one row for every grant so each device has its own set';
+
+create unique index tokens_family_id_uindex
+	on tokens (family_id);
+
+create unique index tokens_access_token_uindex
+	on tokens (access_token);
+
+create unique index tokens_refresh_token_uindex
+	on tokens (refresh_token);
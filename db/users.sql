@@ -6,7 +6,14 @@ create table users
 	username varchar not null,
 	password varchar not null,
 	salt char(32),
-	email varchar
+	email varchar,
+	email_verified boolean default false not null,
+	deletion_scheduled bigint,
+	totp_secret bytea,
+	totp_enabled boolean default false not null,
+	totp_last_step bigint,
+	totp_failures integer default 0 not null,
+	totp_locked_until bigint
 );
 
 comment on table users is 'Contains user IDs associated with the username and password hash';
@@ -0,0 +1,277 @@
+use rocket::http::{ContentType, CookieJar, Status};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::serde::json::Json;
+use rocket::State;
+use rocket_sync_db_pools::postgres;
+use rocket_sync_db_pools::postgres::error::SqlState;
+
+use super::events::record_security_event;
+use super::oauth::OAuthConfig;
+use super::users::{get_session_user_id, random_bytes_l, user_by_email_exists, user_by_name_exists, ARGON_CONFIG};
+use super::verification::send_verification_email;
+use crate::db::UsersDBConnection;
+use crate::mail::Mailer;
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct AccountSettings{
+    pub username: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub deletion_scheduled: Option<u64>,
+    pub two_factor_enabled: bool
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct PasswordChangeRequest{
+    current_password: Option<String>,
+    new_password: Option<String>
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct EmailChangeRequest{
+    password: Option<String>,
+    email: Option<String>
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct UsernameChangeRequest{
+    username: Option<String>
+}
+
+pub fn get_account_settings(conn: &mut postgres::Client, user_id: &String) -> Option<AccountSettings> {
+    return match conn.query_opt("SELECT username, email, email_verified, deletion_scheduled, totp_enabled FROM users WHERE id=$1", &[user_id]) {
+        Ok(Some(row)) => Some(AccountSettings{
+            username: row.get(0),
+            email: row.get(1),
+            email_verified: row.get(2),
+            deletion_scheduled: row.get::<usize, Option<i64>>(3).map(|deletion_scheduled| deletion_scheduled as u64),
+            two_factor_enabled: row.get(4)
+        }),
+        _ => None
+    };
+}
+
+pub fn verify_password(conn: &mut postgres::Client, user_id: &String, password: &String) -> bool {
+    return match conn.query_opt("SELECT password FROM users WHERE id=$1", &[user_id]) {
+        Ok(Some(row)) => {
+            let password_hash: String = row.get(0);
+            argon2::verify_encoded(&password_hash, password.as_bytes()).unwrap_or(false)
+        },
+        _ => false
+    };
+}
+
+// 0 - changed, 1 - wrong current password, 2 - new password empty, 3 - internal error
+// The browser the password was changed in stays logged in, every other remember-me session is logged out
+fn change_password(conn: &mut postgres::Client, user_id: &String, current_password: &String, new_password: &String, series_id: &String) -> u8 {
+    if new_password.is_empty() {
+        return 2;
+    }
+    if !verify_password(conn, user_id, current_password) {
+        return 1;
+    }
+
+    let salt: String = random_bytes_l(32);
+    let password_hash: String = match argon2::hash_encoded(new_password.as_bytes(), salt.as_bytes(), &ARGON_CONFIG) {
+        Ok(password_hash) => password_hash,
+        Err(_e) => return 3
+    };
+
+    let mut transaction = match conn.transaction() {
+        Ok(transaction) => transaction,
+        Err(_e) => return 3
+    };
+    let updated = transaction.execute("UPDATE users SET password=$1, salt=$2 WHERE id=$3", &[&password_hash, &salt, user_id]);
+    let sessions = transaction.execute("DELETE FROM login_tokens WHERE user_id=$1 AND series_id<>$2", &[user_id, series_id]);
+    if updated.is_err() || sessions.is_err() || transaction.commit().is_err() {
+        return 3;
+    }
+
+    record_security_event(conn, user_id, None, "password_changed", "the password was changed in the account settings");
+    return 0;
+}
+
+// 0 - changed, 1 - wrong password, 2 - invalid email, 3 - email taken, 4 - internal error
+// Returns the previous address as well, so it can be told about the change. The new address has to be verified again,
+// and reset links that were sent to the old address stop working.
+fn change_email(conn: &mut postgres::Client, user_id: &String, password: &String, email: &String) -> (u8, Option<String>) {
+    if !email.contains('@') {
+        return (2, None);
+    }
+    if !verify_password(conn, user_id, password) {
+        return (1, None);
+    }
+
+    match user_by_email_exists(conn, email) {
+        0 => {},
+        1 => return (3, None),
+        _ => return (4, None)
+    }
+
+    let previous_email: Option<String> = match get_account_settings(conn, user_id) {
+        Some(settings) => settings.email,
+        None => return (4, None)
+    };
+
+    let mut transaction = match conn.transaction() {
+        Ok(transaction) => transaction,
+        Err(_e) => return (4, None)
+    };
+    let updated = transaction.execute("UPDATE users SET email=$1, email_verified = false WHERE id=$2", &[email, user_id]);
+    let resets = transaction.execute("DELETE FROM password_resets WHERE user_id=$1", &[user_id]);
+    if updated.is_err() || resets.is_err() || transaction.commit().is_err() {
+        return (4, None);
+    }
+
+    let details: String = format!("the email address was changed from {} to {}", previous_email.clone().unwrap_or_default(), email);
+    record_security_event(conn, user_id, None, "email_changed", &details);
+    return (0, previous_email);
+}
+
+// 0 - changed, 1 - username taken, 2 - username empty, 3 - internal error
+fn change_username(conn: &mut postgres::Client, user_id: &String, username: &String) -> u8 {
+    if username.trim().is_empty() {
+        return 2;
+    }
+
+    match user_by_name_exists(conn, username) {
+        0 => {},
+        1 => return 1,
+        _ => return 3
+    }
+
+    let previous_username: String = match get_account_settings(conn, user_id) {
+        Some(settings) => settings.username,
+        None => return 3
+    };
+
+    // The unique index still catches a username that was taken in the meantime
+    match conn.execute("UPDATE users SET username=$1 WHERE id=$2", &[username, user_id]) {
+        Ok(_rows) => {},
+        Err(e) if e.code() == Some(&SqlState::UNIQUE_VIOLATION) => return 1,
+        Err(_e) => return 3
+    }
+
+    let details: String = format!("the username was changed from {} to {}", previous_username, username);
+    record_security_event(conn, user_id, None, "username_changed", &details);
+    return 0;
+}
+
+#[get("/")]
+async fn settings(conn: UsersDBConnection, cookies: &CookieJar<'_>) -> (Status, (ContentType, String)) {
+    let user_id: String = match get_session_user_id(&conn, cookies).await {
+        Some(user_id) => user_id,
+        None => return (Status::Unauthorized, (ContentType::JSON, String::from("{\"status_code\": 401, \"error\": \"not logged in\", \"success\": false}")))
+    };
+
+    let res: Option<AccountSettings> = conn.run(move |c| {
+        return get_account_settings(c, &user_id);
+    }).await;
+
+    return match res {
+        Some(settings) => (Status::Ok, (ContentType::JSON, rocket::serde::json::serde_json::to_string_pretty(&settings).unwrap())),
+        None => (Status::InternalServerError, (ContentType::JSON, String::from("{\"status_code\": 500, \"error\": \"internal server error\", \"success\": false}")))
+    };
+}
+
+#[post("/password", format = "json", data = "<input>")]
+async fn password(conn: UsersDBConnection, cookies: &CookieJar<'_>, input: Json<PasswordChangeRequest>) -> (Status, (ContentType, String)) {
+    let user_id: String = match get_session_user_id(&conn, cookies).await {
+        Some(user_id) => user_id,
+        None => return (Status::Unauthorized, (ContentType::JSON, String::from("{\"status_code\": 401, \"error\": \"not logged in\", \"success\": false}")))
+    };
+    let series_id: String = match cookies.get_private("series_id") {
+        Some(cookie) => String::from(cookie.value()),
+        None => return (Status::Unauthorized, (ContentType::JSON, String::from("{\"status_code\": 401, \"error\": \"not logged in\", \"success\": false}")))
+    };
+
+    let req = input.into_inner();
+    if req.current_password.is_none() || req.new_password.is_none() {
+        return (Status::BadRequest, (ContentType::JSON, String::from("{\"status_code\": 400, \"error\": \"invalid request\", \"success\": false}")));
+    }
+    let current_password: String = req.current_password.unwrap();
+    let new_password: String = req.new_password.unwrap();
+
+    let res: u8 = conn.run(move |c| {
+        return change_password(c, &user_id, &current_password, &new_password, &series_id);
+    }).await;
+
+    return match res {
+        0 => (Status::Ok, (ContentType::JSON, String::from("{\"status_code\": 200, \"error\": null, \"success\": true}"))),
+        1 => (Status::Forbidden, (ContentType::JSON, String::from("{\"status_code\": 403, \"error\": \"wrong password\", \"success\": false}"))),
+        2 => (Status::BadRequest, (ContentType::JSON, String::from("{\"status_code\": 400, \"error\": \"password empty\", \"success\": false}"))),
+        _ => (Status::InternalServerError, (ContentType::JSON, String::from("{\"status_code\": 500, \"error\": \"internal server error\", \"success\": false}")))
+    };
+}
+
+#[post("/email", format = "json", data = "<input>")]
+async fn email(conn: UsersDBConnection, cookies: &CookieJar<'_>, mailer: &State<Mailer>, config: &State<OAuthConfig>, input: Json<EmailChangeRequest>) -> (Status, (ContentType, String)) {
+    let user_id: String = match get_session_user_id(&conn, cookies).await {
+        Some(user_id) => user_id,
+        None => return (Status::Unauthorized, (ContentType::JSON, String::from("{\"status_code\": 401, \"error\": \"not logged in\", \"success\": false}")))
+    };
+
+    let req = input.into_inner();
+    if req.password.is_none() || req.email.is_none() {
+        return (Status::BadRequest, (ContentType::JSON, String::from("{\"status_code\": 400, \"error\": \"invalid request\", \"success\": false}")));
+    }
+    let password: String = req.password.unwrap();
+    let email: String = String::from(req.email.unwrap().trim());
+    let email_: String = email.clone();
+    let user_id_: String = user_id.clone();
+
+    let (res, previous_email): (u8, Option<String>) = conn.run(move |c| {
+        return change_email(c, &user_id_, &password, &email_);
+    }).await;
+
+    match res {
+        0 => {},
+        1 => return (Status::Forbidden, (ContentType::JSON, String::from("{\"status_code\": 403, \"error\": \"wrong password\", \"success\": false}"))),
+        2 => return (Status::BadRequest, (ContentType::JSON, String::from("{\"status_code\": 400, \"error\": \"invalid email\", \"success\": false}"))),
+        3 => return (Status::Forbidden, (ContentType::JSON, String::from("{\"status_code\": 403, \"error\": \"email taken\", \"success\": false}"))),
+        _ => return (Status::InternalServerError, (ContentType::JSON, String::from("{\"status_code\": 500, \"error\": \"internal server error\", \"success\": false}")))
+    }
+
+    // Someone who took over the session can't quietly move the account to their own address
+    if let Some(previous_email) = previous_email {
+        let body: String = format!("The email address of your account was changed to {}. If you didn't do this, reset your password and contact us right away.", email);
+        mailer.send_in_background(&previous_email, "Your email address was changed", body);
+    }
+    if !send_verification_email(&conn, mailer, config, user_id.clone()).await {
+        println!("Could not send the verification email to user {}", user_id);
+    }
+
+    return (Status::Ok, (ContentType::JSON, String::from("{\"status_code\": 200, \"error\": null, \"success\": true}")));
+}
+
+#[post("/username", format = "json", data = "<input>")]
+async fn username(conn: UsersDBConnection, cookies: &CookieJar<'_>, input: Json<UsernameChangeRequest>) -> (Status, (ContentType, String)) {
+    let user_id: String = match get_session_user_id(&conn, cookies).await {
+        Some(user_id) => user_id,
+        None => return (Status::Unauthorized, (ContentType::JSON, String::from("{\"status_code\": 401, \"error\": \"not logged in\", \"success\": false}")))
+    };
+
+    let username: String = match input.into_inner().username {
+        Some(username) => username,
+        None => return (Status::BadRequest, (ContentType::JSON, String::from("{\"status_code\": 400, \"error\": \"invalid request\", \"success\": false}")))
+    };
+
+    let res: u8 = conn.run(move |c| {
+        return change_username(c, &user_id, &username);
+    }).await;
+
+    return match res {
+        0 => (Status::Ok, (ContentType::JSON, String::from("{\"status_code\": 200, \"error\": null, \"success\": true}"))),
+        1 => (Status::Forbidden, (ContentType::JSON, String::from("{\"status_code\": 403, \"error\": \"username taken\", \"success\": false}"))),
+        2 => (Status::BadRequest, (ContentType::JSON, String::from("{\"status_code\": 400, \"error\": \"username empty\", \"success\": false}"))),
+        _ => (Status::InternalServerError, (ContentType::JSON, String::from("{\"status_code\": 500, \"error\": \"internal server error\", \"success\": false}")))
+    };
+}
+
+pub fn stage() -> Vec<rocket::Route> {
+    routes![settings, password, email, username]
+}
\ No newline at end of file
@@ -0,0 +1,176 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rocket::http::{ContentType, CookieJar, Status};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::serde::json::Json;
+use rocket_sync_db_pools::postgres;
+
+use super::clients::{get_client_info, ClientInfo};
+use super::scopes::{get_scope_details, scope_union};
+use super::events::record_security_event;
+use super::users::get_session_user_id;
+use crate::db::UsersDBConnection;
+
+// A set of tokens the client holds for the user, every login on a device creates its own set
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ConnectedDevice{
+    pub user_agent: Option<String>,
+    pub scopes: Vec<String>,
+    pub created: u64,
+    pub last_used: Option<u64>
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ConnectedApp{
+    pub client_id: String,
+    pub client_name: String,
+    pub internal: bool,
+    pub scopes: Vec<String>,
+    pub last_used: Option<u64>,
+    pub devices: Vec<ConnectedDevice>
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct RevokeAppRequest{
+    client_id: Option<String>
+}
+
+fn scope_display_names(conn: &mut postgres::Client, scope: u64) -> Vec<String>{
+    return get_scope_details(conn, scope).unwrap_or_default().into_iter().map(|scope| scope.display_name).collect();
+}
+
+// Returns every client that holds usable tokens for the user or that the user gave consent to
+pub fn get_connected_apps(conn: &mut postgres::Client, user_id: &String) -> Option<Vec<ConnectedApp>>{
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    let now: i64 = since_the_epoch.as_secs() as i64;
+
+    // Client id -> scope and devices, the scope combines every token set and the stored consent
+    let mut apps: HashMap<String, (u64, Vec<ConnectedDevice>)> = HashMap::new();
+
+    let tokens = conn.query("SELECT client_id, scope, user_agent, created, last_used FROM tokens WHERE user_id=$1 AND refresh_token_revoked = false AND family_expire > $2 AND refresh_token_expire > $2 ORDER BY created",
+                            &[user_id, &now]);
+    if tokens.is_err() {
+        return None;
+    }
+
+    for row in tokens.unwrap() {
+        let client_id: String = row.get(0);
+        let scope: i64 = row.get(1);
+        let user_agent: Option<String> = row.get(2);
+        let created: i64 = row.get(3);
+        let last_used: Option<i64> = row.get(4);
+
+        let app = apps.entry(String::from(client_id.trim_end())).or_insert((scope as u64, Vec::new()));
+        app.0 = scope_union(app.0, scope as u64);
+        app.1.push(ConnectedDevice{
+            user_agent,
+            scopes: scope_display_names(conn, scope as u64),
+            created: created as u64,
+            last_used: last_used.map(|last_used| last_used as u64)
+        });
+    }
+
+    let consents = conn.query("SELECT client_id, scope FROM consents WHERE user_id=$1", &[user_id]);
+    if consents.is_err() {
+        return None;
+    }
+
+    for row in consents.unwrap() {
+        let client_id: String = row.get(0);
+        let scope: i64 = row.get(1);
+
+        let app = apps.entry(String::from(client_id.trim_end())).or_insert((scope as u64, Vec::new()));
+        app.0 = scope_union(app.0, scope as u64);
+    }
+
+    let mut connected_apps: Vec<ConnectedApp> = Vec::new();
+    for (client_id, (scope, devices)) in apps {
+        let client_info: ClientInfo = get_client_info(conn, &client_id);
+        if !client_info.success {
+            continue;
+        }
+
+        connected_apps.push(ConnectedApp{
+            client_id,
+            client_name: client_info.client_name.unwrap(),
+            internal: client_info.internal,
+            scopes: scope_display_names(conn, scope),
+            last_used: devices.iter().filter_map(|device| device.last_used).max(),
+            devices
+        });
+    }
+
+    connected_apps.sort_by_key(|app| Reverse(app.last_used));
+    return Some(connected_apps);
+}
+
+// Removes everything that lets the client act for the user, the client has to ask for consent again on the next login
+pub fn revoke_connected_app(conn: &mut postgres::Client, user_id: &String, client_id: &String) -> bool{
+    let mut transaction = match conn.transaction() {
+        Ok(transaction) => transaction,
+        Err(_e) => return false
+    };
+
+    let tokens = transaction.execute("DELETE FROM tokens WHERE user_id=$1 AND client_id=$2", &[user_id, client_id]);
+    let codes = transaction.execute("DELETE FROM authorization_codes WHERE user_id=$1 AND client_id=$2", &[user_id, client_id]);
+    let consents = transaction.execute("DELETE FROM consents WHERE user_id=$1 AND client_id=$2", &[user_id, client_id]);
+
+    if tokens.is_err() || codes.is_err() || consents.is_err() || transaction.commit().is_err() {
+        return false;
+    }
+
+    record_security_event(conn, user_id, Some(client_id), "app_revoked", "the user revoked the access of a connected app");
+    return true;
+}
+
+#[get("/")]
+async fn list(conn: UsersDBConnection, cookies: &CookieJar<'_>) -> (Status, (ContentType, String)) {
+    let user_id: String = match get_session_user_id(&conn, cookies).await {
+        Some(user_id) => user_id,
+        None => return (Status::Unauthorized, (ContentType::JSON, String::from("{\"status_code\": 401, \"error\": \"not logged in\", \"success\": false}")))
+    };
+
+    let res: Option<Vec<ConnectedApp>> = conn.run(move |c| {
+        return get_connected_apps(c, &user_id);
+    }).await;
+
+    return match res {
+        Some(apps) => (Status::Ok, (ContentType::JSON, rocket::serde::json::serde_json::to_string_pretty(&apps).unwrap())),
+        None => (Status::InternalServerError, (ContentType::JSON, String::from("{\"status_code\": 500, \"error\": \"internal server error\", \"success\": false}")))
+    };
+}
+
+#[post("/revoke", format = "json", data = "<input>")]
+async fn revoke(conn: UsersDBConnection, cookies: &CookieJar<'_>, input: Json<RevokeAppRequest>) -> (Status, (ContentType, String)) {
+    let user_id: String = match get_session_user_id(&conn, cookies).await {
+        Some(user_id) => user_id,
+        None => return (Status::Unauthorized, (ContentType::JSON, String::from("{\"status_code\": 401, \"error\": \"not logged in\", \"success\": false}")))
+    };
+
+    let client_id: String = match input.into_inner().client_id {
+        Some(client_id) => client_id,
+        None => return (Status::BadRequest, (ContentType::JSON, String::from("{\"status_code\": 400, \"error\": \"invalid request\", \"success\": false}")))
+    };
+
+    let res: bool = conn.run(move |c| {
+        return revoke_connected_app(c, &user_id, &client_id);
+    }).await;
+
+    return if res {
+        (Status::Ok, (ContentType::JSON, String::from("{\"status_code\": 200, \"error\": null, \"success\": true}")))
+    } else {
+        (Status::InternalServerError, (ContentType::JSON, String::from("{\"status_code\": 500, \"error\": \"internal server error\", \"success\": false}")))
+    };
+}
+
+pub fn stage() -> Vec<rocket::Route> {
+    routes![list, revoke]
+}
\ No newline at end of file
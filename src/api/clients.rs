@@ -1,50 +1,96 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use argon2::{Config, ThreadMode, Variant, Version};
+use rand::Rng;
 use rocket::http::{Status, ContentType};
 use rocket_sync_db_pools::postgres;
 use rocket::serde::{Serialize, Deserialize};
+use rocket::serde::json::Json;
+use crate::api::users::to_hex_string;
+use crate::api::scopes::all_scopes;
 use crate::db::UsersDBConnection;
 
+// A client can have two active secrets at once, so a new secret can be rolled out while the old one still works
+const MAX_ACTIVE_SECRETS: i64 = 2;
+
+// Client secrets are 32 random bytes, guessing one is hopeless whatever the hash costs. The password settings would
+// only let anyone who knows a client id make every token request cost two slow hashes before it is authenticated.
+const CLIENT_SECRET_ARGON_CONFIG: Config = Config {
+    variant: Variant::Argon2id,
+    version: Version::Version13,
+    mem_cost: 1024,
+    time_cost: 1,
+    lanes: 1,
+    thread_mode: ThreadMode::Sequential,
+    secret: &[],
+    ad: &[],
+    hash_length: 32
+};
+
+// Parameters of hashes made with CLIENT_SECRET_ARGON_CONFIG as they appear in the encoded hash
+const CLIENT_SECRET_ARGON_PARAMS: &str = "$m=1024,t=1,p=1$";
+
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "rocket::serde")]
 pub struct ClientInfo{
     pub client_name: Option<String>,
     pub client_id: Option<String>,
     pub internal: bool,
+    pub public: bool,
     pub status_code: Option<u16>,
     pub error: Option<String>,
     pub success: bool
 }
 
-// Possible scopes: 0 - all, 1 - email, 2 - username
-pub fn scope_to_vec(scope: u64) -> Option<Vec<String>> {
-    let mut json: Vec<String> = Vec::new();
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct SecretRotationRequest{
+    client_secret: Option<String>,
+    expires_in: Option<u64>,
+    current_secret_expires_in: Option<u64>
+}
 
-    if scope == 0 {
-        json.insert(0, String::from("Email"));
-        json.insert(1, String::from("Username"));
-        return Some(json);
-    }
-    if scope & 1 == 1 {
-        json.insert(0, String::from("Email"));
-    }
-    if scope & 2 == 2 {
-        json.insert(0, String::from("Username"));
-    }
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct SecretDeletionRequest{
+    client_secret: Option<String>
+}
 
-    if json.is_empty(){
-        return None;
-    }
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct SecretRotationResponse{
+    client_secret: Option<String>,
+    secret_id: Option<String>,
+    current_secret_id: Option<String>,
+    expiration: Option<u64>,
+    status_code: Option<u16>,
+    error: Option<String>,
+    success: bool
+}
 
-    return Some(json);
+impl SecretRotationResponse {
+    fn error(status_code: u16, error: &str) -> SecretRotationResponse {
+        return SecretRotationResponse{
+            client_secret: None,
+            secret_id: None,
+            current_secret_id: None,
+            expiration: None,
+            status_code: Some(status_code),
+            error: Some(status_code.to_string() + "; " + error),
+            success: false
+        };
+    }
 }
 
 pub fn get_client_info(conn: &mut postgres::Client, client_id: &String) -> ClientInfo {
-    let client_info = conn.query_one("SELECT client_name, internal FROM clients WHERE client_id=$1", &[client_id]);
+    let client_info = conn.query_one("SELECT client_name, internal, public FROM clients WHERE client_id=$1", &[client_id]);
 
     if client_info.is_err() {
         return ClientInfo{
             client_name: None,
             client_id: None,
             internal: false,
+            public: false,
             status_code: Some(401),
             error: Some(String::from("401; invalid client id")),
             success: false
@@ -55,10 +101,12 @@ pub fn get_client_info(conn: &mut postgres::Client, client_id: &String) -> Clien
 
     let client_name: String = client_info_raw.get(0);
     let internal: bool = client_info_raw.get(1);
+    let public: bool = client_info_raw.get(2);
 
     return ClientInfo{
         client_name: Some(client_name),
         internal,
+        public,
         client_id: Some(client_id.clone()),
         status_code: Some(200),
         error: None,
@@ -66,6 +114,286 @@ pub fn get_client_info(conn: &mut postgres::Client, client_id: &String) -> Clien
     };
 }
 
+// Returns the id of the active secret that matches, public clients have no secrets.
+// A client has at most two active secrets, so this costs at most two cheap Argon2 verifications. Secrets hashed with
+// the password settings by earlier versions are hashed again with the cheap ones once they are used.
+fn find_client_secret(conn: &mut postgres::Client, client_id: &String, client_secret: &String) -> Option<String> {
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    let now: i64 = since_the_epoch.as_secs() as i64;
+
+    let secrets = conn.query("SELECT client_secrets.secret_id, client_secrets.secret_hash FROM client_secrets JOIN clients ON clients.client_id = client_secrets.client_id WHERE client_secrets.client_id=$1 AND clients.public = false AND (client_secrets.expire IS NULL OR client_secrets.expire > $2) ORDER BY client_secrets.created DESC LIMIT $3",
+                             &[client_id, &now, &MAX_ACTIVE_SECRETS]);
+
+    if secrets.is_err() {
+        return None;
+    }
+
+    for row in secrets.unwrap() {
+        let secret_id: String = row.get(0);
+        let secret_hash: String = row.get(1);
+        if argon2::verify_encoded(&secret_hash, client_secret.as_bytes()).unwrap_or(false) {
+            if !secret_hash.contains(CLIENT_SECRET_ARGON_PARAMS) {
+                if let Some(new_secret_hash) = hash_client_secret(client_secret) {
+                    let _ = conn.execute("UPDATE client_secrets SET secret_hash=$1 WHERE secret_id=$2", &[&new_secret_hash, &secret_id]);
+                }
+            }
+            return Some(secret_id);
+        }
+    }
+
+    return None;
+}
+
+pub fn verify_client_secret(conn: &mut postgres::Client, client_id: &String, client_secret: &String) -> bool {
+    return find_client_secret(conn, client_id, client_secret).is_some();
+}
+
+fn hash_client_secret(client_secret: &str) -> Option<String> {
+    let salt: [u8; 16] = rand::thread_rng().gen();
+    return argon2::hash_encoded(client_secret.as_bytes(), &salt, &CLIENT_SECRET_ARGON_CONFIG).ok();
+}
+
+// Stores the Argon2 hash of a client secret and returns its id, fails if the client already has two active secrets.
+// The client row stays locked until the secret is stored, so concurrent rotations can't add a third one.
+fn add_client_secret(conn: &mut postgres::Client, client_id: &String, client_secret: &str, expire: Option<i64>) -> Option<String> {
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    let now: i64 = since_the_epoch.as_secs() as i64;
+
+    let secret_id: String = to_hex_string(&rand::thread_rng().gen::<[u8; 16]>());
+    let secret_hash: String = hash_client_secret(client_secret)?;
+
+    let mut transaction = match conn.transaction() {
+        Ok(transaction) => transaction,
+        Err(_e) => return None
+    };
+
+    match transaction.query_opt("SELECT client_id FROM clients WHERE client_id=$1 FOR UPDATE", &[client_id]) {
+        Ok(Some(_row)) => {},
+        _ => return None
+    }
+
+    if transaction.execute("DELETE FROM client_secrets WHERE client_id=$1 AND expire <= $2", &[client_id, &now]).is_err() {
+        return None;
+    }
+
+    let active_secrets: i64 = match transaction.query_one("SELECT count(*) FROM client_secrets WHERE client_id=$1", &[client_id]) {
+        Ok(row) => row.get(0),
+        Err(_e) => return None
+    };
+    if active_secrets >= MAX_ACTIVE_SECRETS {
+        return None;
+    }
+
+    let result = transaction.execute("INSERT INTO client_secrets (client_id, secret_id, secret_hash, expire, created) VALUES ($1, $2, $3, $4, $5)",
+                                     &[client_id, &secret_id, &secret_hash, &expire, &now]);
+    if result.is_err() || transaction.commit().is_err() {
+        return None;
+    }
+
+    return Some(secret_id);
+}
+
+// Creates a new secret for a client authenticated with one of its current secrets.
+// The current secret can be given an expiry, so it keeps working until every deployment uses the new one.
+fn rotate_client_secret(conn: &mut postgres::Client, client_id: &String, request: &SecretRotationRequest) -> SecretRotationResponse {
+    let current_secret_id: String = match request.client_secret.as_ref().and_then(|client_secret| find_client_secret(conn, client_id, client_secret)) {
+        Some(secret_id) => secret_id,
+        None => return SecretRotationResponse::error(401, "invalid client credentials")
+    };
+
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+
+    let expiration: Option<u64> = request.expires_in.map(|expires_in| since_the_epoch.as_secs() + expires_in);
+    let client_secret: String = to_hex_string(&rand::thread_rng().gen::<[u8; 32]>());
+
+    let secret_id: String = match add_client_secret(conn, client_id, &client_secret, expiration.map(|expiration| expiration as i64)) {
+        Some(secret_id) => secret_id,
+        None => return SecretRotationResponse::error(409, "client already has two active secrets")
+    };
+
+    if let Some(current_secret_expires_in) = request.current_secret_expires_in {
+        let current_secret_expire: i64 = (since_the_epoch.as_secs() + current_secret_expires_in) as i64;
+        let result = conn.execute("UPDATE client_secrets SET expire=$1 WHERE client_id=$2 AND secret_id=$3",
+                                  &[&current_secret_expire, client_id, &current_secret_id]);
+        if result.is_err() {
+            return SecretRotationResponse::error(500, "internal server error");
+        }
+    }
+
+    return SecretRotationResponse{
+        client_secret: Some(client_secret),
+        secret_id: Some(secret_id),
+        current_secret_id: Some(current_secret_id),
+        expiration,
+        status_code: Some(201),
+        error: None,
+        success: true
+    };
+}
+
+// Deletes one of the client's secrets before it expires, for example the old one once the new one is deployed or
+// one that leaked. The client authenticates with a current secret, which can't be the one being deleted.
+// Status codes: 200 deleted, 401 invalid credentials, 404 unknown secret, 409 deleting the authenticating secret, 500 database error
+fn delete_client_secret(conn: &mut postgres::Client, client_id: &String, secret_id: &String, request: &SecretDeletionRequest) -> u16 {
+    let current_secret_id: String = match request.client_secret.as_ref().and_then(|client_secret| find_client_secret(conn, client_id, client_secret)) {
+        Some(secret_id) => secret_id,
+        None => return 401
+    };
+
+    if &current_secret_id == secret_id {
+        return 409;
+    }
+
+    return match conn.execute("DELETE FROM client_secrets WHERE client_id=$1 AND secret_id=$2", &[client_id, secret_id]) {
+        Ok(0) => 404,
+        Ok(_rows) => 200,
+        Err(_e) => 500
+    };
+}
+
+// Moves plaintext secrets from the clients table to client_secrets as hashes, returns the number of migrated clients
+pub fn migrate_plaintext_client_secrets(conn: &mut postgres::Client) -> u64 {
+    let rows = match conn.query("SELECT client_id, client_secret FROM clients WHERE client_secret IS NOT NULL", &[]) {
+        Ok(rows) => rows,
+        Err(_e) => return 0
+    };
+
+    let mut migrated: u64 = 0;
+    for row in rows {
+        let client_id: String = row.get(0);
+        let client_secret: String = row.get(1);
+
+        if add_client_secret(conn, &client_id, &String::from(client_secret.trim_end()), None).is_none() {
+            continue;
+        }
+        if conn.execute("UPDATE clients SET client_secret = NULL WHERE client_id=$1", &[&client_id]).is_ok() {
+            migrated += 1;
+        }
+    }
+
+    return migrated;
+}
+
+// Returns the uri with the port removed if it is an http uri on a loopback address, native apps listen on whatever
+// port is free so those uris match on any port (RFC 8252 section 7.3)
+fn loopback_uri_without_port(uri: &str) -> Option<String> {
+    let rest: &str = uri.strip_prefix("http://")?;
+
+    let authority_end: usize = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let (authority, path) = rest.split_at(authority_end);
+
+    let host: &str = if authority.starts_with('[') {
+        match authority.find(']') {
+            Some(host_end) => &authority[..=host_end],
+            None => return None
+        }
+    } else {
+        authority.split(':').next().unwrap_or_default()
+    };
+
+    let port: &str = &authority[host.len()..];
+    if !port.is_empty() && (!port.starts_with(':') || !port[1..].chars().all(|c| c.is_ascii_digit())) {
+        return None;
+    }
+    if host != "127.0.0.1" && host != "[::1]" && host != "localhost" {
+        return None;
+    }
+
+    return Some(String::from("http://") + host + path);
+}
+
+// Redirect uris must be registered for the client and match exactly, except for the port of loopback uris
+// registered with any_loopback_port
+pub fn is_redirect_uri_registered(conn: &mut postgres::Client, client_id: &String, redirect_uri: &String) -> bool {
+    let registered = conn.query("SELECT redirect_uri, any_loopback_port FROM client_redirect_uris WHERE client_id=$1", &[client_id]);
+
+    if registered.is_err() {
+        return false;
+    }
+
+    for row in registered.unwrap() {
+        let registered_uri: String = row.get(0);
+        let any_loopback_port: bool = row.get(1);
+
+        if redirect_uri_matches(&registered_uri, any_loopback_port, redirect_uri) {
+            return true;
+        }
+    }
+
+    return false;
+}
+
+fn redirect_uri_matches(registered_uri: &String, any_loopback_port: bool, redirect_uri: &String) -> bool {
+    if registered_uri == redirect_uri {
+        return true;
+    }
+
+    if any_loopback_port {
+        let registered_without_port: Option<String> = loopback_uri_without_port(registered_uri);
+        return registered_without_port.is_some() && registered_without_port == loopback_uri_without_port(redirect_uri);
+    }
+
+    return false;
+}
+
+// Scope a client may request for itself with the client credentials grant, 0 if it may not request any.
+// Clients with all_scopes_allowed get every scope registered right now.
+pub fn get_client_allowed_scope(conn: &mut postgres::Client, client_id: &String) -> Option<u64> {
+    let row = match conn.query_opt("SELECT allowed_scope, all_scopes_allowed FROM clients WHERE client_id=$1", &[client_id]) {
+        Ok(Some(row)) => row,
+        _ => return None
+    };
+
+    let allowed_scope: i64 = row.get(0);
+    let all_scopes_allowed: bool = row.get(1);
+    if all_scopes_allowed {
+        return Some(all_scopes(conn));
+    }
+
+    return Some(allowed_scope as u64);
+}
+
+// Algorithm the client wants its ID tokens signed with, None if it didn't register one
+pub fn get_client_signing_algorithm(conn: &mut postgres::Client, client_id: &String) -> Option<String> {
+    return match conn.query_opt("SELECT id_token_signed_response_alg FROM clients WHERE client_id=$1", &[client_id]) {
+        Ok(Some(row)) => row.get(0),
+        _ => None
+    };
+}
+
+// Returns the authentication method registered for the client and its JWK set for private_key_jwt.
+// Public clients always use "none", confidential clients one of client_secret_basic, client_secret_post or private_key_jwt.
+pub fn get_client_auth_method(conn: &mut postgres::Client, client_id: &String) -> Option<(String, Option<String>)> {
+    let client_info = conn.query_opt("SELECT public, token_endpoint_auth_method, jwks FROM clients WHERE client_id=$1", &[client_id]);
+
+    let client_info_raw = match client_info {
+        Ok(Some(row)) => row,
+        _ => return None
+    };
+
+    let public: bool = client_info_raw.get(0);
+    let method: String = client_info_raw.get(1);
+    let jwks: Option<String> = client_info_raw.get(2);
+
+    if public {
+        return Some((String::from("none"), None));
+    }
+    if method == "none" {
+        return None;
+    }
+
+    return Some((method, jwks));
+}
+
 #[get("/<client_id>")]
 async fn get(conn: UsersDBConnection, client_id: String) -> (Status, (ContentType, String)) {
     let res: ClientInfo = conn.run(move |c| {
@@ -76,6 +404,99 @@ async fn get(conn: UsersDBConnection, client_id: String) -> (Status, (ContentTyp
     return (Status::from_code(res.status_code.unwrap()).unwrap(), (ContentType::JSON, res_json));
 }
 
+#[post("/<client_id>/secrets", format = "json", data = "<input>")]
+async fn rotate_secret(conn: UsersDBConnection, client_id: String, input: Json<SecretRotationRequest>) -> (Status, (ContentType, String)) {
+    let req = input.into_inner();
+
+    let res: SecretRotationResponse = conn.run(move |c| {
+        return rotate_client_secret(c, &client_id, &req);
+    }).await;
+
+    let res_json: String = rocket::serde::json::serde_json::to_string_pretty(&res).unwrap();
+    return (Status::from_code(res.status_code.unwrap()).unwrap(), (ContentType::JSON, res_json));
+}
+
+#[delete("/<client_id>/secrets/<secret_id>", format = "json", data = "<input>")]
+async fn delete_secret(conn: UsersDBConnection, client_id: String, secret_id: String, input: Json<SecretDeletionRequest>) -> (Status, (ContentType, String)) {
+    let req = input.into_inner();
+
+    let status_code: u16 = conn.run(move |c| {
+        return delete_client_secret(c, &client_id, &secret_id, &req);
+    }).await;
+
+    let res_json: String = match status_code {
+        200 => String::from("{\"status_code\": 200, \"error\": null, \"success\": true}"),
+        401 => String::from("{\"status_code\": 401, \"error\": \"invalid client credentials\", \"success\": false}"),
+        404 => String::from("{\"status_code\": 404, \"error\": \"unknown secret\", \"success\": false}"),
+        409 => String::from("{\"status_code\": 409, \"error\": \"the secret used to authenticate can't be deleted\", \"success\": false}"),
+        _ => String::from("{\"status_code\": 500, \"error\": \"internal server error\", \"success\": false}")
+    };
+    return (Status::from_code(status_code).unwrap(), (ContentType::JSON, res_json));
+}
+
 pub fn stage() -> Vec<rocket::Route> {
-    routes![get]
+    routes![get, rotate_secret, delete_secret]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hash_client_secret, loopback_uri_without_port, redirect_uri_matches, CLIENT_SECRET_ARGON_PARAMS};
+
+    fn matches(registered_uri: &str, any_loopback_port: bool, redirect_uri: &str) -> bool {
+        return redirect_uri_matches(&String::from(registered_uri), any_loopback_port, &String::from(redirect_uri));
+    }
+
+    #[test]
+    fn client_secrets_use_cheap_argon_params() {
+        let secret_hash: String = hash_client_secret("secret").unwrap();
+        assert!(secret_hash.contains(CLIENT_SECRET_ARGON_PARAMS));
+        assert!(argon2::verify_encoded(&secret_hash, b"secret").unwrap());
+        assert!(!argon2::verify_encoded(&secret_hash, b"other").unwrap());
+    }
+
+    #[test]
+    fn strips_port_of_loopback_uris() {
+        assert_eq!(loopback_uri_without_port(&String::from("http://127.0.0.1:51004/callback")), Some(String::from("http://127.0.0.1/callback")));
+        assert_eq!(loopback_uri_without_port(&String::from("http://[::1]:8080/callback?x=1")), Some(String::from("http://[::1]/callback?x=1")));
+        assert_eq!(loopback_uri_without_port(&String::from("http://localhost:3000")), Some(String::from("http://localhost")));
+        assert_eq!(loopback_uri_without_port(&String::from("http://localhost/callback")), Some(String::from("http://localhost/callback")));
+    }
+
+    #[test]
+    fn rejects_uris_that_are_not_loopback() {
+        assert_eq!(loopback_uri_without_port(&String::from("https://127.0.0.1:8080/callback")), None);
+        assert_eq!(loopback_uri_without_port(&String::from("http://example.com:8080/callback")), None);
+        assert_eq!(loopback_uri_without_port(&String::from("http://localhost.example.com:8080/callback")), None);
+        assert_eq!(loopback_uri_without_port(&String::from("http://127.0.0.1:80a/callback")), None);
+        assert_eq!(loopback_uri_without_port(&String::from("http://[::1/callback")), None);
+    }
+
+    #[test]
+    fn loopback_uris_match_on_any_port() {
+        assert!(matches("http://127.0.0.1/callback", true, "http://127.0.0.1:51004/callback"));
+        assert!(matches("http://127.0.0.1:8080/callback", true, "http://127.0.0.1/callback"));
+        assert!(matches("http://[::1]/callback", true, "http://[::1]:51004/callback"));
+        assert!(matches("http://localhost/callback", true, "http://localhost:3000/callback"));
+        assert!(matches("http://localhost/callback", true, "http://localhost/callback"));
+    }
+
+    #[test]
+    fn loopback_uris_still_match_host_and_path() {
+        assert!(!matches("http://127.0.0.1/callback", true, "http://127.0.0.1:51004/other"));
+        assert!(!matches("http://127.0.0.1/callback", true, "http://[::1]:51004/callback"));
+        assert!(!matches("http://localhost/callback", true, "http://127.0.0.1:3000/callback"));
+    }
+
+    #[test]
+    fn ports_only_ignored_when_allowed() {
+        assert!(!matches("http://127.0.0.1/callback", false, "http://127.0.0.1:51004/callback"));
+        assert!(matches("http://127.0.0.1:51004/callback", false, "http://127.0.0.1:51004/callback"));
+    }
+
+    #[test]
+    fn other_hosts_match_exactly() {
+        assert!(!matches("http://example.com/callback", true, "http://example.com:8080/callback"));
+        assert!(!matches("https://example.com/callback", true, "https://example.com:8443/callback"));
+        assert!(matches("https://example.com/callback", true, "https://example.com/callback"));
+    }
 }
\ No newline at end of file
@@ -0,0 +1,45 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rocket_sync_db_pools::postgres;
+
+use super::clients::{get_client_info, ClientInfo};
+use super::scopes::scope_includes;
+
+// Internal clients are our own applications and never ask for consent, other clients need the user's approval for
+// every scope they request. Returns true if the client may get the scope without asking the user.
+pub fn has_consent(conn: &mut postgres::Client, user_id: &String, client_id: &String, scope: u64) -> bool{
+    let client_info: ClientInfo = get_client_info(conn, client_id);
+    if !client_info.success {
+        return false;
+    }
+    if client_info.internal {
+        return true;
+    }
+
+    let consent = conn.query_opt("SELECT scope FROM consents WHERE user_id=$1 AND client_id=$2", &[user_id, client_id]);
+
+    return match consent {
+        Ok(Some(row)) => scope_includes(row.get::<usize, i64>(0) as u64, scope),
+        _ => false
+    };
+}
+
+// Remembers that the user approved the scope for the client, scopes approved earlier stay approved
+pub fn grant_consent(conn: &mut postgres::Client, user_id: &String, client_id: &String, scope: u64) -> bool{
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    let granted: i64 = since_the_epoch.as_secs() as i64;
+    let scope_: i64 = scope as i64;
+
+    // 0 isn't a grant, callers pass the scope parse_scope expanded it to
+    if scope == 0 {
+        return false;
+    }
+
+    let result = conn.execute("INSERT INTO consents (user_id, client_id, scope, granted) VALUES ($1, $2, $3, $4) \
+                               ON CONFLICT (user_id, client_id) DO UPDATE SET scope = consents.scope | excluded.scope, granted = excluded.granted",
+                              &[user_id, client_id, &scope_, &granted]);
+    return result.is_ok();
+}
\ No newline at end of file
@@ -0,0 +1,288 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rand::Rng;
+use rocket::http::{ContentType, CookieJar, Cookie, Status};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::serde::json::Json;
+use rocket::serde::json::Value;
+use rocket::serde::json::serde_json::json;
+use rocket::State;
+use rocket_sync_db_pools::postgres;
+
+use super::account::verify_password;
+use super::events::record_security_event;
+use super::keys::{get_signing_key, sign_jwt, SigningKey};
+use super::oauth::OAuthConfig;
+use super::users::{get_session_user_id, to_hex_string};
+use crate::db::UsersDBConnection;
+
+// OpenID RISC event types, sent to clients that registered an account_events_uri
+const ACCOUNT_DISABLED_EVENT: &str = "https://schemas.openid.net/secevent/risc/event-type/account-disabled";
+const ACCOUNT_ENABLED_EVENT: &str = "https://schemas.openid.net/secevent/risc/event-type/account-enabled";
+const ACCOUNT_PURGED_EVENT: &str = "https://schemas.openid.net/secevent/risc/event-type/account-purged";
+
+//Duration in seconds (one hour)
+pub const DELETION_CHECK_INTERVAL: u64 = 60*60;
+
+#[derive(Deserialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct DeletionConfig{
+    pub account_deletion_grace_period: u64
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct DeletionRequest{
+    password: Option<String>
+}
+
+// A security event token and the uri of the client it is pushed to
+pub struct AccountEvent{
+    uri: String,
+    token: String
+}
+
+// Creates a security event token (RFC 8417) about the user for every client that holds tokens or consent for them and
+// registered an account_events_uri. They have to be created before the tokens and consents are deleted.
+fn create_account_events(conn: &mut postgres::Client, config: &OAuthConfig, user_id: &String, event: &str) -> Vec<AccountEvent> {
+    let clients = conn.query("SELECT client_id, account_events_uri FROM clients WHERE account_events_uri IS NOT NULL AND client_id IN (SELECT client_id FROM tokens WHERE user_id=$1 UNION SELECT client_id FROM consents WHERE user_id=$1)",
+                             &[user_id]);
+    let clients = match clients {
+        Ok(clients) => clients,
+        Err(_e) => return Vec::new()
+    };
+    if clients.is_empty() {
+        return Vec::new();
+    }
+
+    let key: SigningKey = match get_signing_key(conn, &config.signing_algorithm) {
+        Some(key) => key,
+        None => return Vec::new()
+    };
+
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+
+    let mut events: Vec<AccountEvent> = Vec::new();
+    for row in clients {
+        let client_id: String = row.get(0);
+        let uri: String = row.get(1);
+
+        let mut claims: Value = json!({
+            "iss": config.issuer,
+            "aud": client_id.trim_end(),
+            "iat": since_the_epoch.as_secs(),
+            "jti": to_hex_string(&rand::thread_rng().gen::<[u8; 16]>()),
+            "events": {}
+        });
+        claims["events"][event] = json!({
+            "subject": {
+                "subject_type": "iss-sub",
+                "iss": config.issuer,
+                "sub": user_id
+            }
+        });
+
+        if let Some(token) = sign_jwt(&key, &claims) {
+            events.push(AccountEvent{
+                uri,
+                token
+            });
+        }
+    }
+
+    return events;
+}
+
+// Pushes the events to the clients (RFC 8935) without waiting for them, a client that can't be reached only misses the event
+pub fn deliver_account_events(events: Vec<AccountEvent>) {
+    if events.is_empty() {
+        return;
+    }
+
+    rocket::tokio::task::spawn_blocking(move || {
+        for event in events {
+            let result = ureq::post(&event.uri)
+                .set("Content-Type", "application/secevent+jwt")
+                .set("Accept", "application/json")
+                .send_string(&event.token);
+            if result.is_err() {
+                println!("Failed to deliver an account event to {}", event.uri);
+            }
+        }
+    });
+}
+
+// Accounts waiting to be deleted can still log in to the account pages to cancel, but no client gets tokens for them
+pub fn is_deletion_scheduled(conn: &mut postgres::Client, user_id: &String) -> bool {
+    return match conn.query_opt("SELECT deletion_scheduled FROM users WHERE id=$1", &[user_id]) {
+        Ok(Some(row)) => row.get::<usize, Option<i64>>(0).is_some(),
+        _ => false
+    };
+}
+
+// Deletes the user and everything stored about them, returns the events for the clients that have to be told
+pub fn delete_account(conn: &mut postgres::Client, config: &OAuthConfig, user_id: &String) -> Option<Vec<AccountEvent>> {
+    let events: Vec<AccountEvent> = create_account_events(conn, config, user_id, ACCOUNT_PURGED_EVENT);
+
+    let mut transaction = match conn.transaction() {
+        Ok(transaction) => transaction,
+        Err(_e) => return None
+    };
+
+    let statements: [&str; 9] = [
+        "DELETE FROM rotated_refresh_tokens WHERE family_id IN (SELECT family_id FROM tokens WHERE user_id=$1)",
+        "DELETE FROM tokens WHERE user_id=$1",
+        "DELETE FROM login_tokens WHERE user_id=$1",
+        "DELETE FROM authorization_codes WHERE user_id=$1",
+        "DELETE FROM consents WHERE user_id=$1",
+        "DELETE FROM password_resets WHERE user_id=$1",
+        "DELETE FROM recovery_codes WHERE user_id=$1",
+        "DELETE FROM security_events WHERE user_id=$1",
+        "DELETE FROM users WHERE id=$1"
+    ];
+    for statement in statements {
+        if transaction.execute(statement, &[user_id]).is_err() {
+            return None;
+        }
+    }
+    if transaction.commit().is_err() {
+        return None;
+    }
+
+    return Some(events);
+}
+
+// Deletes every account whose grace period is over, run regularly in the background
+pub fn delete_due_accounts(conn: &mut postgres::Client, config: &OAuthConfig) -> Vec<AccountEvent> {
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    let now: i64 = since_the_epoch.as_secs() as i64;
+
+    let users = match conn.query("SELECT id FROM users WHERE deletion_scheduled <= $1", &[&now]) {
+        Ok(users) => users,
+        Err(_e) => return Vec::new()
+    };
+
+    let mut events: Vec<AccountEvent> = Vec::new();
+    for row in users {
+        let user_id: String = row.get(0);
+        if let Some(user_events) = delete_account(conn, config, &user_id) {
+            events.extend(user_events);
+        }
+    }
+    return events;
+}
+
+// 0 - deletion scheduled, 1 - deleted right away, 2 - wrong password, 3 - internal error
+// Every client loses access right away and the user is logged out everywhere except in the current browser, so they
+// can still cancel. Without a grace period the account is deleted immediately.
+fn schedule_account_deletion(conn: &mut postgres::Client, config: &OAuthConfig, user_id: &String, password: &String, series_id: &String, grace_period: u64) -> (u8, Vec<AccountEvent>) {
+    if !verify_password(conn, user_id, password) {
+        return (2, Vec::new());
+    }
+
+    if grace_period == 0 {
+        return match delete_account(conn, config, user_id) {
+            Some(events) => (1, events),
+            None => (3, Vec::new())
+        };
+    }
+
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    let deletion_scheduled: i64 = (since_the_epoch.as_secs() + grace_period) as i64;
+
+    let events: Vec<AccountEvent> = create_account_events(conn, config, user_id, ACCOUNT_DISABLED_EVENT);
+
+    let mut transaction = match conn.transaction() {
+        Ok(transaction) => transaction,
+        Err(_e) => return (3, Vec::new())
+    };
+    let scheduled = transaction.execute("UPDATE users SET deletion_scheduled=$1 WHERE id=$2", &[&deletion_scheduled, user_id]);
+    let tokens = transaction.execute("UPDATE tokens SET access_token_revoked = true, refresh_token_revoked = true WHERE user_id=$1", &[user_id]);
+    let codes = transaction.execute("DELETE FROM authorization_codes WHERE user_id=$1", &[user_id]);
+    let sessions = transaction.execute("DELETE FROM login_tokens WHERE user_id=$1 AND series_id<>$2", &[user_id, series_id]);
+    if scheduled.is_err() || tokens.is_err() || codes.is_err() || sessions.is_err() || transaction.commit().is_err() {
+        return (3, Vec::new());
+    }
+
+    record_security_event(conn, user_id, None, "account_deletion_scheduled", "the user asked for their account to be deleted");
+    return (0, events);
+}
+
+// Returns false if no deletion was scheduled
+fn cancel_account_deletion(conn: &mut postgres::Client, config: &OAuthConfig, user_id: &String) -> (bool, Vec<AccountEvent>) {
+    let result = conn.execute("UPDATE users SET deletion_scheduled = NULL WHERE id=$1 AND deletion_scheduled IS NOT NULL", &[user_id]);
+    if result.is_err() || result.unwrap() != 1 {
+        return (false, Vec::new());
+    }
+
+    record_security_event(conn, user_id, None, "account_deletion_cancelled", "the user cancelled the deletion of their account");
+    return (true, create_account_events(conn, config, user_id, ACCOUNT_ENABLED_EVENT));
+}
+
+#[post("/delete", format = "json", data = "<input>")]
+async fn delete(conn: UsersDBConnection, cookies: &CookieJar<'_>, config: &State<OAuthConfig>, deletion_config: &State<DeletionConfig>, input: Json<DeletionRequest>) -> (Status, (ContentType, String)) {
+    let user_id: String = match get_session_user_id(&conn, cookies).await {
+        Some(user_id) => user_id,
+        None => return (Status::Unauthorized, (ContentType::JSON, String::from("{\"status_code\": 401, \"error\": \"not logged in\", \"success\": false}")))
+    };
+    let series_id: String = match cookies.get_private("series_id") {
+        Some(cookie) => String::from(cookie.value()),
+        None => return (Status::Unauthorized, (ContentType::JSON, String::from("{\"status_code\": 401, \"error\": \"not logged in\", \"success\": false}")))
+    };
+    let password: String = match input.into_inner().password {
+        Some(password) => password,
+        None => return (Status::BadRequest, (ContentType::JSON, String::from("{\"status_code\": 400, \"error\": \"invalid request\", \"success\": false}")))
+    };
+
+    let config: OAuthConfig = config.inner().clone();
+    let grace_period: u64 = deletion_config.account_deletion_grace_period;
+    let (res, events): (u8, Vec<AccountEvent>) = conn.run(move |c| {
+        return schedule_account_deletion(c, &config, &user_id, &password, &series_id, grace_period);
+    }).await;
+    deliver_account_events(events);
+
+    return match res {
+        0 => (Status::Ok, (ContentType::JSON, String::from("{\"status_code\": 200, \"error\": null, \"deleted\": false, \"success\": true}"))),
+        1 => {
+            for name in ["user_id", "series_id", "token"] {
+                cookies.remove_private(Cookie::named(name));
+            }
+            (Status::Ok, (ContentType::JSON, String::from("{\"status_code\": 200, \"error\": null, \"deleted\": true, \"success\": true}")))
+        },
+        2 => (Status::Forbidden, (ContentType::JSON, String::from("{\"status_code\": 403, \"error\": \"wrong password\", \"success\": false}"))),
+        _ => (Status::InternalServerError, (ContentType::JSON, String::from("{\"status_code\": 500, \"error\": \"internal server error\", \"success\": false}")))
+    };
+}
+
+#[post("/delete/cancel")]
+async fn cancel(conn: UsersDBConnection, cookies: &CookieJar<'_>, config: &State<OAuthConfig>) -> (Status, (ContentType, String)) {
+    let user_id: String = match get_session_user_id(&conn, cookies).await {
+        Some(user_id) => user_id,
+        None => return (Status::Unauthorized, (ContentType::JSON, String::from("{\"status_code\": 401, \"error\": \"not logged in\", \"success\": false}")))
+    };
+
+    let config: OAuthConfig = config.inner().clone();
+    let (cancelled, events): (bool, Vec<AccountEvent>) = conn.run(move |c| {
+        return cancel_account_deletion(c, &config, &user_id);
+    }).await;
+    deliver_account_events(events);
+
+    return if cancelled {
+        (Status::Ok, (ContentType::JSON, String::from("{\"status_code\": 200, \"error\": null, \"success\": true}")))
+    } else {
+        (Status::Conflict, (ContentType::JSON, String::from("{\"status_code\": 409, \"error\": \"no deletion scheduled\", \"success\": false}")))
+    };
+}
+
+pub fn stage() -> Vec<rocket::Route> {
+    routes![delete, cancel]
+}
\ No newline at end of file
@@ -0,0 +1,64 @@
+use rocket::http::{ContentType, Status};
+use rocket::serde::json::Value;
+use rocket::serde::json::serde_json::json;
+use rocket::State;
+
+use crate::api::scopes::get_scopes;
+use crate::api::keys::{get_jwks, SIGNING_ALGORITHMS};
+use crate::api::oauth::OAuthConfig;
+use crate::db::UsersDBConnection;
+
+// Server metadata shared by OpenID Connect Discovery and RFC 8414, lists only what is mounted in api::stage and frontend::stage
+async fn server_metadata(conn: &UsersDBConnection, config: &OAuthConfig) -> Value {
+    let scopes: Vec<String> = conn.run(move |c| {
+        return get_scopes(c).into_iter().map(|scope| scope.name).collect();
+    }).await;
+
+    return json!({
+        "issuer": config.issuer,
+        "authorization_endpoint": config.issuer.clone() + "/authorize",
+        "token_endpoint": config.issuer.clone() + "/token",
+        "userinfo_endpoint": config.issuer.clone() + "/userinfo",
+        "introspection_endpoint": config.issuer.clone() + "/introspect",
+        "revocation_endpoint": config.issuer.clone() + "/revoke",
+        "jwks_uri": config.issuer.clone() + "/jwks.json",
+        "scopes_supported": scopes,
+        "response_types_supported": ["code"],
+        "response_modes_supported": ["query"],
+        "grant_types_supported": ["authorization_code", "refresh_token", "client_credentials"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": SIGNING_ALGORITHMS,
+        "token_endpoint_auth_methods_supported": ["client_secret_basic", "client_secret_post", "private_key_jwt", "none"],
+        "token_endpoint_auth_signing_alg_values_supported": SIGNING_ALGORITHMS,
+        "introspection_endpoint_auth_methods_supported": ["client_secret_basic", "client_secret_post", "private_key_jwt"],
+        "revocation_endpoint_auth_methods_supported": ["client_secret_basic", "client_secret_post", "private_key_jwt", "none"],
+        "code_challenge_methods_supported": ["S256", "plain"],
+        "claims_supported": ["iss", "sub", "aud", "iat", "exp", "auth_time", "nonce", "amr", "email", "email_verified", "preferred_username"]
+    });
+}
+
+#[get("/.well-known/openid-configuration")]
+async fn openid_configuration(conn: UsersDBConnection, config: &State<OAuthConfig>) -> (Status, (ContentType, String)) {
+    return (Status::Ok, (ContentType::JSON, server_metadata(&conn, config).await.to_string()));
+}
+
+#[get("/.well-known/oauth-authorization-server")]
+async fn oauth_authorization_server(conn: UsersDBConnection, config: &State<OAuthConfig>) -> (Status, (ContentType, String)) {
+    return (Status::Ok, (ContentType::JSON, server_metadata(&conn, config).await.to_string()));
+}
+
+#[get("/jwks.json")]
+async fn jwks(conn: UsersDBConnection) -> (Status, (ContentType, String)) {
+    let res: Option<Value> = conn.run(move |c| {
+        return get_jwks(c);
+    }).await;
+
+    return match res {
+        Some(keys) => (Status::Ok, (ContentType::JSON, keys.to_string())),
+        None => (Status::InternalServerError, (ContentType::JSON, String::from("{\"success\": false, \"status_code\": 500, \"error\": \"internal server error\"}")))
+    }
+}
+
+pub fn stage() -> Vec<rocket::Route> {
+    routes![openid_configuration, oauth_authorization_server, jwks]
+}
\ No newline at end of file
@@ -0,0 +1,20 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rocket_sync_db_pools::postgres;
+
+// Records a security relevant event for the user, failing to record it must never fail the request itself
+pub fn record_security_event(conn: &mut postgres::Client, user_id: &String, client_id: Option<&String>, event: &str, details: &str){
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    let created: i64 = since_the_epoch.as_secs() as i64;
+    let event_: String = String::from(event);
+    let details_: String = String::from(details);
+
+    let result = conn.execute("INSERT INTO security_events (user_id, client_id, event, details, created) VALUES ($1, $2, $3, $4, $5)",
+                              &[user_id, &client_id, &event_, &details_, &created]);
+    if result.is_err() {
+        println!("Failed to record security event {} for user {}", event, user_id);
+    }
+}
\ No newline at end of file
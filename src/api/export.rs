@@ -0,0 +1,162 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rocket::http::{ContentType, CookieJar, Header, Status};
+use rocket::serde::json::Value;
+use rocket::serde::json::serde_json::json;
+use rocket_sync_db_pools::postgres;
+
+use super::account::{get_account_settings, AccountSettings};
+use super::clients::{get_client_info, ClientInfo};
+use super::scopes::scope_to_vec;
+use super::users::get_session_user_id;
+use crate::db::UsersDBConnection;
+
+// The export is downloaded as a file instead of being shown in the browser
+#[derive(Responder)]
+#[response(content_type = "json")]
+struct DataExport{
+    body: String,
+    disposition: Header<'static>
+}
+
+fn client_name(conn: &mut postgres::Client, client_id: &str) -> Option<String> {
+    let client_info: ClientInfo = get_client_info(conn, &String::from(client_id.trim_end()));
+    return client_info.client_name;
+}
+
+// Everything stored about the user, the password hash and the hashes of their tokens are left out
+pub fn export_account_data(conn: &mut postgres::Client, user_id: &String) -> Option<Value> {
+    let settings: AccountSettings = get_account_settings(conn, user_id)?;
+
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    let now: i64 = since_the_epoch.as_secs() as i64;
+
+    // Every login is recorded as a security event, token sets are deleted once they can't be used anymore
+    let login_rows = match conn.query("SELECT client_id, details, created FROM security_events WHERE user_id=$1 AND event = 'login' ORDER BY created", &[user_id]) {
+        Ok(login_rows) => login_rows,
+        Err(_e) => return None
+    };
+    let mut logins: Vec<Value> = Vec::new();
+    for row in login_rows {
+        let client_id: Option<String> = row.get(0);
+        let details: Option<String> = row.get(1);
+        let created: i64 = row.get(2);
+
+        logins.push(json!({
+            "client_id": client_id.as_ref().map(|client_id| String::from(client_id.trim_end())),
+            "client_name": client_id.and_then(|client_id| client_name(conn, &client_id)),
+            "details": details,
+            "created": created
+        }));
+    }
+
+    // The token sets that are still stored, the ones that can still be refreshed are active
+    let token_sets = match conn.query("SELECT client_id, scope, user_agent, created, last_used, refresh_token_revoked = false AND family_expire > $2 AND refresh_token_expire > $2 FROM tokens WHERE user_id=$1 ORDER BY created",
+                                      &[user_id, &now]) {
+        Ok(token_sets) => token_sets,
+        Err(_e) => return None
+    };
+    let mut applications: Vec<Value> = Vec::new();
+    for row in token_sets {
+        let client_id: String = row.get(0);
+        let scope: i64 = row.get(1);
+        let user_agent: Option<String> = row.get(2);
+        let created: i64 = row.get(3);
+        let last_used: Option<i64> = row.get(4);
+        let active: bool = row.get(5);
+
+        applications.push(json!({
+            "client_id": client_id.trim_end(),
+            "client_name": client_name(conn, &client_id),
+            "scopes": scope_to_vec(conn, scope as u64).unwrap_or_default(),
+            "user_agent": user_agent,
+            "created": created,
+            "last_used": last_used,
+            "active": active
+        }));
+    }
+
+    let browser_sessions: i64 = match conn.query_one("SELECT count(*) FROM login_tokens WHERE user_id=$1", &[user_id]) {
+        Ok(row) => row.get(0),
+        Err(_e) => return None
+    };
+
+    let consent_rows = match conn.query("SELECT client_id, scope, granted FROM consents WHERE user_id=$1 ORDER BY granted", &[user_id]) {
+        Ok(consent_rows) => consent_rows,
+        Err(_e) => return None
+    };
+    let mut consents: Vec<Value> = Vec::new();
+    for row in consent_rows {
+        let client_id: String = row.get(0);
+        let scope: i64 = row.get(1);
+        let granted: i64 = row.get(2);
+
+        consents.push(json!({
+            "client_id": client_id.trim_end(),
+            "client_name": client_name(conn, &client_id),
+            "scopes": scope_to_vec(conn, scope as u64).unwrap_or_default(),
+            "granted": granted
+        }));
+    }
+
+    let event_rows = match conn.query("SELECT event, client_id, details, created FROM security_events WHERE user_id=$1 AND event <> 'login' ORDER BY created", &[user_id]) {
+        Ok(event_rows) => event_rows,
+        Err(_e) => return None
+    };
+    let mut security_events: Vec<Value> = Vec::new();
+    for row in event_rows {
+        let client_id: Option<String> = row.get(1);
+        let created: i64 = row.get(3);
+
+        security_events.push(json!({
+            "event": row.get::<usize, String>(0),
+            "client_id": client_id.map(|client_id| String::from(client_id.trim_end())),
+            "details": row.get::<usize, Option<String>>(2),
+            "created": created
+        }));
+    }
+
+    return Some(json!({
+        "exported": now,
+        "profile": {
+            "user_id": user_id,
+            "username": settings.username,
+            "email": settings.email,
+            "email_verified": settings.email_verified,
+            "deletion_scheduled": settings.deletion_scheduled,
+            "two_factor_enabled": settings.two_factor_enabled
+        },
+        "logins": logins,
+        "applications": applications,
+        "browser_sessions": browser_sessions,
+        "consents": consents,
+        "security_events": security_events
+    }));
+}
+
+#[get("/export")]
+async fn export(conn: UsersDBConnection, cookies: &CookieJar<'_>) -> Result<DataExport, (Status, (ContentType, String))> {
+    let user_id: String = match get_session_user_id(&conn, cookies).await {
+        Some(user_id) => user_id,
+        None => return Err((Status::Unauthorized, (ContentType::JSON, String::from("{\"status_code\": 401, \"error\": \"not logged in\", \"success\": false}"))))
+    };
+
+    let res: Option<Value> = conn.run(move |c| {
+        return export_account_data(c, &user_id);
+    }).await;
+
+    return match res {
+        Some(data) => Ok(DataExport{
+            body: rocket::serde::json::serde_json::to_string_pretty(&data).unwrap(),
+            disposition: Header::new("Content-Disposition", "attachment; filename=\"account-data.json\"")
+        }),
+        None => Err((Status::InternalServerError, (ContentType::JSON, String::from("{\"status_code\": 500, \"error\": \"internal server error\", \"success\": false}"))))
+    };
+}
+
+pub fn stage() -> Vec<rocket::Route> {
+    routes![export]
+}
\ No newline at end of file
@@ -0,0 +1,350 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rand::Rng;
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair, RsaKeyPair, RsaPublicKeyComponents, UnparsedPublicKey, ECDSA_P256_SHA256_FIXED, ECDSA_P256_SHA256_FIXED_SIGNING, ED25519, RSA_PKCS1_2048_8192_SHA256, RSA_PKCS1_SHA256};
+use rocket::serde::json::serde_json::json;
+use rocket::serde::json::Value;
+use rocket_sync_db_pools::postgres;
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use rsa::pkcs1::{DecodeRsaPublicKey, EncodeRsaPublicKey};
+use rsa::pkcs8::EncodePrivateKey;
+use rsa::traits::PublicKeyParts;
+
+use super::users::to_hex_string;
+
+//Duration in seconds (thirty days)
+const KEY_ROTATION_INTERVAL: u64 = 60*60*24*30;
+
+const RSA_KEY_BITS: usize = 2048;
+
+// Algorithms tokens can be signed with: RS256 (RSA PKCS#1 v1.5 with SHA-256, required by OpenID Connect),
+// ES256 (ECDSA P-256 with SHA-256), EdDSA (Ed25519)
+pub const SIGNING_ALGORITHMS: [&str; 3] = ["RS256", "ES256", "EdDSA"];
+
+pub struct SigningKey{
+    pub kid: String,
+    pub algorithm: String,
+    pub private_key: Vec<u8>
+}
+
+fn base64_url(bytes: &[u8]) -> String {
+    return base64::encode_config(bytes, base64::URL_SAFE_NO_PAD);
+}
+
+fn base64_url_decode(value: &str) -> Option<Vec<u8>> {
+    return base64::decode_config(value, base64::URL_SAFE_NO_PAD).ok();
+}
+
+// Returns a new PKCS#8 private key and the matching public key for the algorithm
+fn generate_key_pair(algorithm: &String) -> Option<(Vec<u8>, Vec<u8>)>{
+    let rng = SystemRandom::new();
+
+    let key_pair: (Vec<u8>, Vec<u8>) = if algorithm == "RS256" {
+        // ring can only sign with RSA keys, they are generated with the rsa crate.
+        // The public key is stored as a PKCS#1 RSAPublicKey.
+        let key = match RsaPrivateKey::new(&mut rand::thread_rng(), RSA_KEY_BITS) {
+            Ok(key) => key,
+            Err(_e) => return None
+        };
+        let pkcs8 = match key.to_pkcs8_der() {
+            Ok(pkcs8) => pkcs8,
+            Err(_e) => return None
+        };
+        let public_key = match key.to_public_key().to_pkcs1_der() {
+            Ok(public_key) => public_key,
+            Err(_e) => return None
+        };
+        (pkcs8.as_bytes().to_vec(), public_key.as_bytes().to_vec())
+    }else if algorithm == "ES256" {
+        let pkcs8 = match EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng) {
+            Ok(pkcs8) => pkcs8,
+            Err(_e) => return None
+        };
+        let key_pair = match EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng) {
+            Ok(key_pair) => key_pair,
+            Err(_e) => return None
+        };
+        (pkcs8.as_ref().to_vec(), key_pair.public_key().as_ref().to_vec())
+    }else if algorithm == "EdDSA" {
+        let pkcs8 = match Ed25519KeyPair::generate_pkcs8(&rng) {
+            Ok(pkcs8) => pkcs8,
+            Err(_e) => return None
+        };
+        let key_pair = match Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()) {
+            Ok(key_pair) => key_pair,
+            Err(_e) => return None
+        };
+        (pkcs8.as_ref().to_vec(), key_pair.public_key().as_ref().to_vec())
+    }else{
+        return None;
+    };
+
+    return Some(key_pair);
+}
+
+fn generate_signing_key(conn: &mut postgres::Client, algorithm: &String) -> Option<SigningKey>{
+    let (private_key, public_key): (Vec<u8>, Vec<u8>) = generate_key_pair(algorithm)?;
+
+    let kid: String = to_hex_string(&rand::thread_rng().gen::<[u8; 16]>());
+
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    let created: i64 = since_the_epoch.as_secs() as i64;
+
+    let result = conn.execute("INSERT INTO signing_keys (kid, algorithm, private_key, public_key, created) VALUES ($1, $2, $3, $4, $5)",
+                              &[&kid, algorithm, &private_key, &public_key, &created]);
+    if result.is_err() {
+        return None;
+    }
+
+    return Some(SigningKey{
+        kid,
+        algorithm: algorithm.clone(),
+        private_key
+    });
+}
+
+// Returns the newest key for the algorithm, a new key is generated when the newest one is older than the rotation interval.
+// Older keys stay in the table so tokens they signed can still be verified.
+pub fn get_signing_key(conn: &mut postgres::Client, algorithm: &String) -> Option<SigningKey>{
+    let result = conn.query_opt("SELECT kid, algorithm, private_key, created FROM signing_keys WHERE algorithm=$1 ORDER BY created DESC LIMIT 1", &[algorithm]);
+
+    if result.is_err() {
+        return None;
+    }
+
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+
+    if let Some(row) = result.unwrap() {
+        let created: i64 = row.get(3);
+        if created as u64 + KEY_ROTATION_INTERVAL > since_the_epoch.as_secs() {
+            return Some(SigningKey{
+                kid: row.get(0),
+                algorithm: row.get(1),
+                private_key: row.get(2)
+            });
+        }
+    }
+
+    return generate_signing_key(conn, algorithm);
+}
+
+// Returns the public key as a JWK (RFC 7517), None if the stored key can't be read
+fn public_jwk(kid: &String, algorithm: &String, public_key: &[u8]) -> Option<Value>{
+    if algorithm == "RS256" {
+        let public_key: RsaPublicKey = match RsaPublicKey::from_pkcs1_der(public_key) {
+            Ok(public_key) => public_key,
+            Err(_e) => return None
+        };
+        return Some(json!({
+            "kty": "RSA",
+            "n": base64_url(&public_key.n().to_bytes_be()),
+            "e": base64_url(&public_key.e().to_bytes_be()),
+            "use": "sig",
+            "alg": algorithm,
+            "kid": kid
+        }));
+    }else if algorithm == "ES256" {
+        // Uncompressed point: 0x04 || x || y
+        if public_key.len() != 65 {
+            return None;
+        }
+        return Some(json!({
+            "kty": "EC",
+            "crv": "P-256",
+            "x": base64_url(&public_key[1..33]),
+            "y": base64_url(&public_key[33..65]),
+            "use": "sig",
+            "alg": algorithm,
+            "kid": kid
+        }));
+    }else if algorithm == "EdDSA" {
+        return Some(json!({
+            "kty": "OKP",
+            "crv": "Ed25519",
+            "x": base64_url(public_key),
+            "use": "sig",
+            "alg": algorithm,
+            "kid": kid
+        }));
+    }
+
+    return None;
+}
+
+// Returns the public keys of the current and previous rotation interval as a JWK set (RFC 7517).
+// Keys stay published for one more interval after they are replaced, which outlives every token they signed.
+pub fn get_jwks(conn: &mut postgres::Client) -> Option<Value>{
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    let oldest: i64 = since_the_epoch.as_secs().saturating_sub(KEY_ROTATION_INTERVAL * 2) as i64;
+
+    let result = conn.query("SELECT kid, algorithm, public_key FROM signing_keys WHERE created > $1 ORDER BY created DESC", &[&oldest]);
+
+    if result.is_err() {
+        return None;
+    }
+
+    let mut keys: Vec<Value> = Vec::new();
+    for row in result.unwrap() {
+        let kid: String = row.get(0);
+        let algorithm: String = row.get(1);
+        let public_key: Vec<u8> = row.get(2);
+
+        if let Some(jwk) = public_jwk(&kid, &algorithm, &public_key) {
+            keys.push(jwk);
+        }
+    }
+
+    return Some(json!({ "keys": keys }));
+}
+
+// Returns a compact JWS with the claims as the payload
+pub fn sign_jwt(key: &SigningKey, claims: &Value) -> Option<String>{
+    let header = json!({
+        "alg": key.algorithm,
+        "typ": "JWT",
+        "kid": key.kid
+    });
+
+    let signing_input: String = base64_url(header.to_string().as_bytes()) + "." + &base64_url(claims.to_string().as_bytes());
+
+    let signature: Vec<u8> = if key.algorithm == "RS256" {
+        let key_pair = match RsaKeyPair::from_pkcs8(&key.private_key) {
+            Ok(key_pair) => key_pair,
+            Err(_e) => return None
+        };
+        let mut signature: Vec<u8> = vec![0; key_pair.public().modulus_len()];
+        if key_pair.sign(&RSA_PKCS1_SHA256, &SystemRandom::new(), signing_input.as_bytes(), &mut signature).is_err() {
+            return None;
+        }
+        signature
+    }else if key.algorithm == "ES256" {
+        let rng = SystemRandom::new();
+        let key_pair = match EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &key.private_key, &rng) {
+            Ok(key_pair) => key_pair,
+            Err(_e) => return None
+        };
+        match key_pair.sign(&rng, signing_input.as_bytes()) {
+            Ok(signature) => signature.as_ref().to_vec(),
+            Err(_e) => return None
+        }
+    }else if key.algorithm == "EdDSA" {
+        let key_pair = match Ed25519KeyPair::from_pkcs8(&key.private_key) {
+            Ok(key_pair) => key_pair,
+            Err(_e) => return None
+        };
+        key_pair.sign(signing_input.as_bytes()).as_ref().to_vec()
+    }else{
+        return None;
+    };
+
+    return Some(signing_input + "." + &base64_url(&signature));
+}
+
+
+// Returns the claims of a compact JWS without checking the signature, only for finding the key to verify it with
+pub fn read_jwt_claims(token: &str) -> Option<Value>{
+    let parts: Vec<&str> = token.split('.').collect();
+    if parts.len() != 3 {
+        return None;
+    }
+
+    return match base64_url_decode(parts[1]) {
+        Some(payload) => rocket::serde::json::serde_json::from_slice(&payload).ok(),
+        None => None
+    };
+}
+
+// Verifies a compact JWS against a JWK set (RFC 7517) and returns its claims. Supported algorithms: RS256, ES256, EdDSA.
+// If the header names a kid only that key is tried, otherwise every key of the matching type.
+pub fn verify_jwt(jwks: &Value, token: &str) -> Option<Value>{
+    let parts: Vec<&str> = token.split('.').collect();
+    if parts.len() != 3 {
+        return None;
+    }
+
+    let header: Value = base64_url_decode(parts[0]).and_then(|header| rocket::serde::json::serde_json::from_slice(&header).ok())?;
+    let signature: Vec<u8> = base64_url_decode(parts[2])?;
+    let signing_input: String = String::from(parts[0]) + "." + parts[1];
+
+    let algorithm: &str = header["alg"].as_str().unwrap_or_default();
+    let kid: Option<&str> = header["kid"].as_str();
+
+    let keys: Vec<Value> = match jwks["keys"].as_array() {
+        Some(keys) => keys.clone(),
+        None => return None
+    };
+
+    for key in keys.iter() {
+        if kid.is_some() && key["kid"].as_str() != kid {
+            continue;
+        }
+
+        let verified: bool = if algorithm == "ES256" && key["kty"] == "EC" && key["crv"] == "P-256" {
+            let x: Vec<u8> = base64_url_decode(key["x"].as_str().unwrap_or_default()).unwrap_or_default();
+            let y: Vec<u8> = base64_url_decode(key["y"].as_str().unwrap_or_default()).unwrap_or_default();
+            // Uncompressed point: 0x04 || x || y
+            let mut public_key: Vec<u8> = vec![4];
+            public_key.extend(x);
+            public_key.extend(y);
+            UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, public_key).verify(signing_input.as_bytes(), &signature).is_ok()
+        }else if algorithm == "EdDSA" && key["kty"] == "OKP" && key["crv"] == "Ed25519" {
+            let x: Vec<u8> = base64_url_decode(key["x"].as_str().unwrap_or_default()).unwrap_or_default();
+            UnparsedPublicKey::new(&ED25519, x).verify(signing_input.as_bytes(), &signature).is_ok()
+        }else if algorithm == "RS256" && key["kty"] == "RSA" {
+            let public_key = RsaPublicKeyComponents{
+                n: base64_url_decode(key["n"].as_str().unwrap_or_default()).unwrap_or_default(),
+                e: base64_url_decode(key["e"].as_str().unwrap_or_default()).unwrap_or_default()
+            };
+            public_key.verify(&RSA_PKCS1_2048_8192_SHA256, signing_input.as_bytes(), &signature).is_ok()
+        }else{
+            false
+        };
+
+        if verified {
+            return read_jwt_claims(token);
+        }
+    }
+
+    return None;
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rocket::serde::json::serde_json::json;
+
+    use super::{generate_key_pair, public_jwk, sign_jwt, verify_jwt, SigningKey, SIGNING_ALGORITHMS};
+
+    #[test]
+    fn signed_tokens_verify_against_published_key() {
+        for algorithm in SIGNING_ALGORITHMS {
+            let algorithm: String = String::from(algorithm);
+            let kid: String = String::from("test");
+            let (private_key, public_key) = generate_key_pair(&algorithm).unwrap();
+            let key = SigningKey{
+                kid: kid.clone(),
+                algorithm: algorithm.clone(),
+                private_key
+            };
+            let jwks = json!({ "keys": [public_jwk(&kid, &algorithm, &public_key).unwrap()] });
+
+            let claims = json!({ "sub": "user", "aud": "client" });
+            let token: String = sign_jwt(&key, &claims).unwrap();
+            assert_eq!(verify_jwt(&jwks, &token), Some(claims), "{}", algorithm);
+
+            let mut tampered: String = token.clone();
+            tampered.insert(token.find('.').unwrap() + 1, 'e');
+            assert_eq!(verify_jwt(&jwks, &tampered), None, "{}", algorithm);
+        }
+    }
+}
\ No newline at end of file
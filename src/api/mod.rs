@@ -1,9 +1,100 @@
 pub mod users;
 pub mod clients;
+pub mod oauth;
+pub mod keys;
+pub mod discovery;
+pub mod events;
+pub mod consents;
+pub mod apps;
+pub mod scopes;
+pub mod verification;
+pub mod password_reset;
+pub mod account;
+pub mod deletion;
+pub mod export;
+pub mod two_factor;
+
+use crate::db::{background_pool, BackgroundPool, UsersDBConnection};
 
 pub fn stage() -> rocket::fairing::AdHoc {
     return rocket::fairing::AdHoc::on_ignite("API", |rocket| async {
-        rocket.mount("/api/users", users::stage())
+        rocket.attach(oauth::config())
+            .attach(rocket::fairing::AdHoc::config::<deletion::DeletionConfig>())
+            .mount("/api/users", users::stage())
+            .mount("/api/users", verification::stage())
+            .mount("/api/users", password_reset::stage())
             .mount("/api/clients", clients::stage())
+            .mount("/api/apps", apps::stage())
+            .mount("/api/account", account::stage())
+            .mount("/api/account", deletion::stage())
+            .mount("/api/account", export::stage())
+            .mount("/api/account/two_factor", two_factor::stage())
+            .mount("/", oauth::stage())
+            .mount("/", discovery::stage())
+            .attach(rocket::fairing::AdHoc::on_liftoff("Secret hashing migration", |rocket| Box::pin(async move {
+                // Tokens and client secrets stored before they were hashed at rest are hashed once on startup
+                let token_secret: String = match rocket.state::<oauth::OAuthConfig>() {
+                    Some(config) => config.token_secret.clone(),
+                    None => return
+                };
+                if let Some(conn) = UsersDBConnection::get_one(rocket).await {
+                    let (migrated_tokens, migrated_secrets): (u64, u64) = conn.run(move |c| {
+                        return (users::migrate_plaintext_tokens(c, &token_secret), clients::migrate_plaintext_client_secrets(c));
+                    }).await;
+                    if migrated_tokens > 0 {
+                        println!("Hashed {} plaintext token sets", migrated_tokens);
+                    }
+                    if migrated_secrets > 0 {
+                        println!("Hashed {} plaintext client secrets", migrated_secrets);
+                    }
+                }
+            })))
+            .attach(rocket::fairing::AdHoc::on_liftoff("Scope migration", |rocket| Box::pin(async move {
+                if let Some(conn) = UsersDBConnection::get_one(rocket).await {
+                    let migrated: u64 = conn.run(move |c| {
+                        return scopes::migrate_unexpanded_scopes(c);
+                    }).await;
+                    if migrated > 0 {
+                        println!("Expanded {} grants stored with scope 0", migrated);
+                    }
+                }
+            })))
+            .attach(rocket::fairing::AdHoc::try_on_ignite("Account deletion", |rocket| async {
+                // Accounts are deleted once their grace period is over and rotated refresh tokens once their family
+                // expired, the task keeps a pool with the users_db settings
+                let pool: BackgroundPool = match background_pool(&rocket) {
+                    Some(pool) => pool,
+                    None => {
+                        println!("Invalid users_db configuration, accounts can't be deleted");
+                        return Err(rocket);
+                    }
+                };
+                return Ok(rocket.attach(rocket::fairing::AdHoc::on_liftoff("Account deletion", |rocket| Box::pin(async move {
+                    let config: oauth::OAuthConfig = match rocket.state::<oauth::OAuthConfig>() {
+                        Some(config) => config.clone(),
+                        None => return
+                    };
+                    rocket::tokio::spawn(async move {
+                        loop {
+                            let config_: oauth::OAuthConfig = config.clone();
+                            let pool_: BackgroundPool = pool.clone();
+                            let events: Vec<deletion::AccountEvent> = rocket::tokio::task::spawn_blocking(move || {
+                                return match pool_.get() {
+                                    Ok(mut conn) => {
+                                        users::delete_expired_rotated_refresh_tokens(&mut conn);
+                                        deletion::delete_due_accounts(&mut conn, &config_)
+                                    },
+                                    Err(e) => {
+                                        println!("Could not connect to delete accounts: {}", e);
+                                        Vec::new()
+                                    }
+                                };
+                            }).await.unwrap_or_default();
+                            deletion::deliver_account_events(events);
+                            rocket::tokio::time::sleep(std::time::Duration::from_secs(deletion::DELETION_CHECK_INTERVAL)).await;
+                        }
+                    });
+                }))));
+            }))
     });
 }
\ No newline at end of file
@@ -0,0 +1,1324 @@
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rocket::form::Form;
+use rocket::http::{ContentType, CookieJar, Status};
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, Redirect, Responder, Response};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::serde::json::Json;
+use rocket::serde::json::Value;
+use rocket::serde::json::serde_json::json;
+use rocket::State;
+use rocket_dyn_templates::Template;
+use rocket_sync_db_pools::postgres;
+use sha2::{Digest, Sha256};
+use urlencoding::{decode, encode};
+
+use crate::api::clients::{get_client_allowed_scope, get_client_auth_method, get_client_info, get_client_signing_algorithm, is_redirect_uri_registered, verify_client_secret, ClientInfo};
+use crate::api::scopes::{parse_scope, scope_includes, scope_to_vec};
+use crate::api::consents::{grant_consent, has_consent};
+use crate::api::keys::{get_signing_key, read_jwt_claims, sign_jwt, verify_jwt, SIGNING_ALGORITHMS};
+use crate::api::verification::meets_email_requirement;
+use crate::api::deletion::is_deletion_scheduled;
+use crate::api::two_factor::{check_second_factor, login_amr};
+use crate::api::users::{get_access_token_user_id, get_session, get_token_info, get_user_id_by_credentials, hash_token, random_bytes, rotate_refresh_token, start_session, store_client_token, AuthenticationResponse, TokenGrant, UserAgent, CLIENT_TOKEN_DURATION};
+use crate::db::UsersDBConnection;
+
+//Duration in seconds (one minute)
+const AUTHORIZATION_CODE_DURATION: u64 = 60;
+
+//Duration in seconds (one hour)
+const ID_TOKEN_DURATION: u64 = 60*60;
+
+// Client assertion type for private_key_jwt (RFC 7523)
+const CLIENT_ASSERTION_TYPE: &str = "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+
+//Duration in seconds (five minutes), longest a client assertion may be valid for
+const CLIENT_ASSERTION_MAX_LIFETIME: i64 = 5*60;
+
+//Duration in seconds, how far ahead of ours the client's clock may be for iat and nbf
+const CLIENT_ASSERTION_CLOCK_SKEW: i64 = 60;
+
+#[derive(Deserialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct OAuthConfig{
+    pub issuer: String,
+    pub signing_algorithm: String,
+    pub token_secret: String
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct AuthorizationRequest{
+    username: Option<String>,
+    password: Option<String>,
+    client_id: Option<String>,
+    scope: u64,
+    redirect_uri: Option<String>,
+    code_challenge: Option<String>,
+    code_challenge_method: Option<String>,
+    state: Option<String>,
+    nonce: Option<String>,
+    consent: Option<bool>,
+    remember: bool,
+    otp: Option<String>,
+}
+
+// Query of the authorization endpoint (RFC 6749 section 4.1.1, RFC 7636 section 4.3, OpenID Connect Core section 3.1.2.1)
+#[derive(FromForm)]
+struct AuthorizationQuery{
+    response_type: String,
+    client_id: String,
+    scope: String,
+    redirect_uri: String,
+    code_challenge: Option<String>,
+    code_challenge_method: Option<String>,
+    state: Option<String>,
+    nonce: Option<String>,
+    prompt: Option<String>
+}
+
+// What the user approved, stored with the authorization code until the client exchanges it
+struct AuthorizationGrant{
+    grant: TokenGrant,
+    redirect_uri: String,
+    code_challenge: Option<String>,
+    code_challenge_method: Option<String>,
+    nonce: Option<String>
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct AuthorizationResponse{
+    code: Option<String>,
+    redirect_uri: Option<String>,
+    state: Option<String>,
+    status_code: Option<u16>,
+    error: Option<String>,
+    success: bool
+}
+
+impl AuthorizationResponse {
+    fn error(status_code: u16, error: &str) -> AuthorizationResponse {
+        return AuthorizationResponse{
+            code: None,
+            redirect_uri: None,
+            state: None,
+            status_code: Some(status_code),
+            error: Some(status_code.to_string() + "; " + error),
+            success: false
+        };
+    }
+}
+
+// Successful response of the token endpoint (RFC 6749 section 5.1)
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct TokenResponse{
+    pub access_token: String,
+    pub token_type: String,
+    pub expires_in: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+    pub scope: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>
+}
+
+// Error response of the token endpoint (RFC 6749 section 5.2)
+pub struct TokenError{
+    pub status_code: u16,
+    pub error: &'static str,
+    pub error_description: &'static str
+}
+
+impl TokenError {
+    pub fn new(status_code: u16, error: &'static str, error_description: &'static str) -> TokenError {
+        return TokenError{
+            status_code,
+            error,
+            error_description
+        };
+    }
+
+    pub fn server_error() -> TokenError {
+        return TokenError::new(500, "server_error", "Internal server error");
+    }
+}
+
+// JSON response of the token, introspection and revocation endpoints. They can carry tokens, so they are never cached.
+struct OAuthResponse{
+    status: Status,
+    body: Value,
+    basic_challenge: bool
+}
+
+impl OAuthResponse {
+    fn token(res: Result<TokenResponse, TokenError>) -> OAuthResponse {
+        return match res {
+            Ok(res) => OAuthResponse::ok(json!(res)),
+            Err(error) => OAuthResponse::error(error)
+        };
+    }
+
+    fn ok(body: Value) -> OAuthResponse {
+        return OAuthResponse{
+            status: Status::Ok,
+            body,
+            basic_challenge: false
+        };
+    }
+
+    // Failed client authentication, a client that tried HTTP Basic gets a new challenge (RFC 6749 section 5.2)
+    fn invalid_client(basic_used: bool) -> OAuthResponse {
+        let mut res: OAuthResponse = OAuthResponse::error(TokenError::new(401, "invalid_client", "Client authentication failed"));
+        res.basic_challenge = basic_used;
+        return res;
+    }
+
+    fn error(error: TokenError) -> OAuthResponse {
+        return OAuthResponse{
+            status: Status::from_code(error.status_code).unwrap_or(Status::InternalServerError),
+            body: json!({
+                "error": error.error,
+                "error_description": error.error_description
+            }),
+            basic_challenge: false
+        };
+    }
+}
+
+impl<'r> Responder<'r, 'static> for OAuthResponse {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build_from(self.body.to_string().respond_to(request)?);
+        response.status(self.status)
+            .header(ContentType::JSON)
+            .raw_header("Cache-Control", "no-store")
+            .raw_header("Pragma", "no-cache");
+        if self.basic_challenge {
+            response.raw_header("WWW-Authenticate", "Basic realm=\"oauth\"");
+        }
+        return response.ok();
+    }
+}
+
+// Space separated scope names for the scope field of token responses
+pub fn scope_string(conn: &mut postgres::Client, scope: u64) -> String {
+    return scope_to_vec(conn, scope).unwrap_or_default().join(" ");
+}
+
+// Access token sent in the Authorization header (RFC 6750)
+pub struct BearerToken(pub String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for BearerToken {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let header: Option<&str> = request.headers().get_one("Authorization");
+        return match header {
+            Some(header) if header.starts_with("Bearer ") => Outcome::Success(BearerToken(String::from(&header[7..]))),
+            _ => Outcome::Failure((Status::Unauthorized, ()))
+        };
+    }
+}
+
+// Client id and secret sent in the Authorization header (client_secret_basic, RFC 6749 section 2.3.1)
+pub struct BasicCredentials{
+    pub client_id: String,
+    pub client_secret: String
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for BasicCredentials {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let header: &str = match request.headers().get_one("Authorization") {
+            Some(header) if header.starts_with("Basic ") => &header[6..],
+            _ => return Outcome::Failure((Status::Unauthorized, ()))
+        };
+
+        let credentials: String = match base64::decode(header).ok().and_then(|credentials| String::from_utf8(credentials).ok()) {
+            Some(credentials) => credentials,
+            None => return Outcome::Failure((Status::Unauthorized, ()))
+        };
+
+        // Both parts are form-urlencoded before they are joined with a colon
+        return match credentials.split_once(':') {
+            Some((client_id, client_secret)) => match (decode(client_id), decode(client_secret)) {
+                (Ok(client_id), Ok(client_secret)) => Outcome::Success(BasicCredentials{
+                    client_id: client_id.into_owned(),
+                    client_secret: client_secret.into_owned()
+                }),
+                _ => Outcome::Failure((Status::Unauthorized, ()))
+            },
+            None => Outcome::Failure((Status::Unauthorized, ()))
+        };
+    }
+}
+
+// Everything a client may send to authenticate itself, only one method may be used per request
+struct ClientAuthentication{
+    basic: Option<BasicCredentials>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    client_assertion_type: Option<String>,
+    client_assertion: Option<String>
+}
+
+#[derive(FromForm)]
+struct TokenRequest{
+    grant_type: Option<String>,
+    code: Option<String>,
+    redirect_uri: Option<String>,
+    client_id: Option<String>,
+    code_verifier: Option<String>,
+    refresh_token: Option<String>,
+    client_secret: Option<String>,
+    client_assertion_type: Option<String>,
+    client_assertion: Option<String>,
+    scope: Option<String>,
+}
+
+#[derive(FromForm)]
+struct IntrospectionRequest{
+    token: Option<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    client_assertion_type: Option<String>,
+    client_assertion: Option<String>,
+}
+
+#[derive(FromForm)]
+struct RevocationRequest{
+    token: Option<String>,
+    token_type_hint: Option<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    client_assertion_type: Option<String>,
+    client_assertion: Option<String>,
+}
+
+// Returns the expiry of a client assertion that is valid right now and not valid for longer than
+// CLIENT_ASSERTION_MAX_LIFETIME, counted from iat if it is set
+fn client_assertion_expire(claims: &Value, now: i64) -> Option<i64>{
+    let expire: i64 = match claims["exp"].as_i64() {
+        Some(expire) if expire >= now && expire - now <= CLIENT_ASSERTION_MAX_LIFETIME => expire,
+        _ => return None
+    };
+
+    if !claims["iat"].is_null() {
+        match claims["iat"].as_i64() {
+            Some(issued) if issued <= now + CLIENT_ASSERTION_CLOCK_SKEW && expire - issued <= CLIENT_ASSERTION_MAX_LIFETIME => {},
+            _ => return None
+        }
+    }
+    if !claims["nbf"].is_null() {
+        match claims["nbf"].as_i64() {
+            Some(not_before) if not_before <= now + CLIENT_ASSERTION_CLOCK_SKEW => {},
+            _ => return None
+        }
+    }
+
+    return Some(expire);
+}
+
+// Checks a private_key_jwt assertion (RFC 7523) against the JWK set registered for the client.
+// The audience must be the issuer or the token endpoint, and every assertion can only be used once.
+// Assertions are short-lived, so their jti only has to be remembered for a few minutes.
+fn verify_client_assertion(conn: &mut postgres::Client, config: &OAuthConfig, client_id: &String, jwks: Option<String>, client_assertion: &str) -> bool{
+    let jwks: Value = match jwks.and_then(|jwks| rocket::serde::json::serde_json::from_str(&jwks).ok()) {
+        Some(jwks) => jwks,
+        None => return false
+    };
+
+    let claims: Value = match verify_jwt(&jwks, client_assertion) {
+        Some(claims) => claims,
+        None => return false
+    };
+
+    if claims["iss"].as_str() != Some(client_id.as_str()) || claims["sub"].as_str() != Some(client_id.as_str()) {
+        return false;
+    }
+
+    let token_endpoint: String = config.issuer.clone() + "/token";
+    let audience_valid: bool = match &claims["aud"] {
+        Value::String(aud) => aud == &config.issuer || aud == &token_endpoint,
+        Value::Array(aud) => aud.iter().any(|aud| aud == &config.issuer || aud == &token_endpoint),
+        _ => false
+    };
+    if !audience_valid {
+        return false;
+    }
+
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    let now: i64 = since_the_epoch.as_secs() as i64;
+
+    let expire: i64 = match client_assertion_expire(&claims, now) {
+        Some(expire) => expire,
+        None => return false
+    };
+    let jti: String = match claims["jti"].as_str() {
+        Some(jti) => String::from(jti),
+        None => return false
+    };
+
+    if conn.execute("DELETE FROM client_assertions WHERE expire < $1", &[&now]).is_err() {
+        return false;
+    }
+
+    // The primary key rejects an assertion that was already used
+    let result = conn.execute("INSERT INTO client_assertions (client_id, jti, expire) VALUES ($1, $2, $3)", &[client_id, &jti, &expire]);
+    return result.is_ok();
+}
+
+// Authenticates the calling client with the method registered for it.
+// Returns the client id and the method, which is "none" for public clients that only identify themselves.
+fn authenticate_client(conn: &mut postgres::Client, config: &OAuthConfig, auth: ClientAuthentication) -> Option<(String, String)>{
+    let (method_used, client_id): (&str, String) = if let Some(basic) = &auth.basic {
+        if auth.client_secret.is_some() || auth.client_assertion.is_some() {
+            return None;
+        }
+        if auth.client_id.is_some() && auth.client_id.as_ref() != Some(&basic.client_id) {
+            return None;
+        }
+        ("client_secret_basic", basic.client_id.clone())
+    } else if let Some(client_assertion) = &auth.client_assertion {
+        if auth.client_secret.is_some() || auth.client_assertion_type.as_deref() != Some(CLIENT_ASSERTION_TYPE) {
+            return None;
+        }
+        // The client is identified by the unverified subject, the signature is checked once its keys are known
+        let subject: String = match read_jwt_claims(client_assertion) {
+            Some(claims) if claims["sub"].is_string() => String::from(claims["sub"].as_str().unwrap()),
+            _ => return None
+        };
+        if auth.client_id.is_some() && auth.client_id.as_ref() != Some(&subject) {
+            return None;
+        }
+        ("private_key_jwt", subject)
+    } else if auth.client_secret.is_some() {
+        match &auth.client_id {
+            Some(client_id) => ("client_secret_post", client_id.clone()),
+            None => return None
+        }
+    } else {
+        match &auth.client_id {
+            Some(client_id) => ("none", client_id.clone()),
+            None => return None
+        }
+    };
+
+    let (method, jwks): (String, Option<String>) = get_client_auth_method(conn, &client_id)?;
+    if method != method_used {
+        return None;
+    }
+
+    let authenticated: bool = if method_used == "client_secret_basic" {
+        verify_client_secret(conn, &client_id, &auth.basic.unwrap().client_secret)
+    } else if method_used == "client_secret_post" {
+        verify_client_secret(conn, &client_id, &auth.client_secret.unwrap())
+    } else if method_used == "private_key_jwt" {
+        verify_client_assertion(conn, config, &client_id, jwks, &auth.client_assertion.unwrap())
+    } else {
+        true
+    };
+
+    if !authenticated {
+        return None;
+    }
+
+    return Some((client_id, method));
+}
+
+// Code verifiers and plain challenges are 43 to 128 unreserved characters, S256 challenges are always 43 (RFC 7636 section 4.1)
+fn is_pkce_value(value: &str) -> bool{
+    return value.len() >= 43 && value.len() <= 128
+        && value.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_' || c == '~');
+}
+
+// Returns the method to store with a well-formed code challenge, or the error to send back with invalid_request.
+// Public clients must use S256, a plain challenge would be sent in the clear to anyone who can see the redirect.
+fn check_code_challenge(public: bool, code_challenge: &Option<String>, code_challenge_method: &Option<String>) -> Result<Option<String>, &'static str>{
+    let code_challenge: &String = match code_challenge {
+        Some(code_challenge) => code_challenge,
+        None if public => return Err("code challenge required"),
+        None => return Ok(None)
+    };
+
+    let method: String = code_challenge_method.clone().unwrap_or_else(|| String::from("plain"));
+    if method != "S256" && method != "plain" {
+        return Err("unsupported code challenge method");
+    }
+    if public && method != "S256" {
+        return Err("public clients must use the S256 code challenge method");
+    }
+    if !is_pkce_value(code_challenge) {
+        return Err("invalid code challenge");
+    }
+
+    return Ok(Some(method));
+}
+
+// Checks a PKCE code verifier against the challenge stored with the authorization code (RFC 7636)
+fn verify_code_challenge(code_challenge: &str, code_challenge_method: &str, code_verifier: &str) -> bool{
+    if !is_pkce_value(code_verifier) {
+        return false;
+    }
+
+    if code_challenge_method == "S256" {
+        let mut sha = Sha256::default();
+        sha.update(code_verifier.as_bytes());
+        let verifier_hash = sha.finalize();
+        let verifier_encoded: String = base64::encode_config(verifier_hash, base64::URL_SAFE_NO_PAD);
+        return verifier_encoded == code_challenge;
+    }else if code_challenge_method == "plain" {
+        return code_verifier == code_challenge;
+    }
+
+    return false;
+}
+
+fn create_authorization_code(conn: &mut postgres::Client, authorization: AuthorizationGrant, token_secret: &String) -> AuthorizationResponse{
+    let grant: &TokenGrant = &authorization.grant;
+    let client_id: &String = &grant.client_id;
+    let user_id: &String = &grant.user_id;
+    let redirect_uri: &String = &authorization.redirect_uri;
+
+    let client_info: ClientInfo = get_client_info(conn, client_id);
+    if !client_info.success {
+        return AuthorizationResponse::error(401, "invalid client id");
+    }
+
+    if !is_redirect_uri_registered(conn, client_id, redirect_uri) {
+        return AuthorizationResponse::error(400, "invalid redirect uri");
+    }
+
+    if scope_to_vec(conn, grant.scope).is_none() {
+        return AuthorizationResponse::error(400, "invalid scope");
+    }
+
+    if !meets_email_requirement(conn, user_id, client_id) {
+        return AuthorizationResponse::error(403, "email not verified");
+    }
+
+    if is_deletion_scheduled(conn, user_id) {
+        return AuthorizationResponse::error(403, "account scheduled for deletion");
+    }
+
+    // Public clients can't keep a secret, so they have to prove they started the flow with PKCE
+    let code_challenge_method: Option<String> = match check_code_challenge(client_info.public, &authorization.code_challenge, &authorization.code_challenge_method) {
+        Ok(method) => method,
+        Err(error) => return AuthorizationResponse::error(400, error)
+    };
+
+    // Only the hash is stored like every other bearer credential, so codes read from the database can't be redeemed
+    let code: String = random_bytes();
+    let code_hash: String = hash_token(&code, token_secret);
+
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    let code_expire: i64 = (since_the_epoch.as_secs() + AUTHORIZATION_CODE_DURATION) as i64;
+    let auth_time: i64 = since_the_epoch.as_secs() as i64;
+    let scope_: i64 = grant.scope as i64;
+
+    let result = conn.execute("INSERT INTO authorization_codes (code, client_id, user_id, redirect_uri, scope, code_challenge, code_challenge_method, nonce, user_agent, auth_time, code_expire, amr) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)",
+                              &[&code_hash, client_id, user_id, redirect_uri, &scope_, &authorization.code_challenge, &code_challenge_method, &authorization.nonce, &grant.user_agent, &auth_time, &code_expire, &grant.amr.join(" ")]);
+
+    if result.is_err() {
+        return AuthorizationResponse::error(500, "internal server error");
+    }
+
+    return AuthorizationResponse{
+        code: Some(code),
+        redirect_uri: Some(redirect_uri.clone()),
+        state: None,
+        status_code: Some(201),
+        error: None,
+        success: true
+    };
+}
+
+// Returns the claims about the user that the granted scope allows releasing, shared by ID tokens and the UserInfo endpoint
+fn get_user_claims(conn: &mut postgres::Client, user_id: &String, scope: u64) -> Option<Value>{
+    let user_info = conn.query_one("SELECT username, email, email_verified FROM users WHERE id=$1", &[user_id]);
+
+    if user_info.is_err() {
+        return None;
+    }
+
+    let user_info_raw = user_info.unwrap();
+    let username: String = user_info_raw.get(0);
+    let email: Option<String> = user_info_raw.get(1);
+    let email_verified: bool = user_info_raw.get(2);
+
+    let mut claims = json!({
+        "sub": user_id
+    });
+
+    let scopes: Vec<String> = scope_to_vec(conn, scope).unwrap_or_default();
+    if scopes.contains(&String::from("email")) {
+        claims["email"] = json!(email);
+        claims["email_verified"] = Value::Bool(email_verified);
+    }
+    if scopes.contains(&String::from("profile")) {
+        claims["preferred_username"] = Value::String(username);
+    }
+
+    return Some(claims);
+}
+
+// Returns a signed OpenID Connect ID token, the user claims included depend on the granted scope
+fn create_id_token(conn: &mut postgres::Client, config: &OAuthConfig, grant: &TokenGrant, nonce: Option<String>, auth_time: i64) -> Option<String>{
+    let mut claims: Value = get_user_claims(conn, &grant.user_id, grant.scope)?;
+
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+
+    claims["iss"] = Value::String(config.issuer.clone());
+    claims["aud"] = Value::String(grant.client_id.clone());
+    claims["iat"] = json!(since_the_epoch.as_secs());
+    claims["exp"] = json!(since_the_epoch.as_secs() + ID_TOKEN_DURATION);
+    claims["auth_time"] = json!(auth_time);
+    claims["amr"] = json!(grant.amr);
+
+    if let Some(nonce) = nonce {
+        claims["nonce"] = Value::String(nonce);
+    }
+
+    // Clients that registered an algorithm get it, every other client the one set for the server
+    let algorithm: String = get_client_signing_algorithm(conn, &grant.client_id).unwrap_or_else(|| config.signing_algorithm.clone());
+    return match get_signing_key(conn, &algorithm) {
+        Some(key) => sign_jwt(&key, &claims),
+        None => None
+    };
+}
+
+fn exchange_authorization_code(conn: &mut postgres::Client, config: &OAuthConfig, code: &String, client_id: &String, redirect_uri: &String, code_verifier: Option<String>) -> Result<TokenResponse, TokenError>{
+    // Codes are single use, so the row is removed whether or not the exchange succeeds
+    let code_info = conn.query_opt("DELETE FROM authorization_codes WHERE code=$1 RETURNING client_id, user_id, redirect_uri, code_expire, code_challenge, code_challenge_method, scope, nonce, auth_time, user_agent, amr", &[&hash_token(code, &config.token_secret)]);
+
+    let code_info_raw = match code_info {
+        Ok(Some(row)) => row,
+        Ok(None) => return Err(TokenError::new(400, "invalid_grant", "Invalid authorization code")),
+        Err(_e) => return Err(TokenError::server_error())
+    };
+
+    let code_client_id: String = code_info_raw.get(0);
+    let code_redirect_uri: String = code_info_raw.get(2);
+    let code_expire: i64 = code_info_raw.get(3);
+    let code_challenge: Option<String> = code_info_raw.get(4);
+    let code_challenge_method: Option<String> = code_info_raw.get(5);
+    let scope: i64 = code_info_raw.get(6);
+    let nonce: Option<String> = code_info_raw.get(7);
+    let auth_time: i64 = code_info_raw.get(8);
+    let amr_: String = code_info_raw.get(10);
+    let grant = TokenGrant{
+        user_id: code_info_raw.get(1),
+        client_id: code_client_id.clone(),
+        scope: scope as u64,
+        // The token request comes from the client's server, the device is the browser that was authorized
+        user_agent: code_info_raw.get(9),
+        amr: amr_.split_whitespace().map(String::from).collect()
+    };
+
+    if &code_client_id != client_id || &code_redirect_uri != redirect_uri {
+        return Err(TokenError::new(400, "invalid_grant", "Invalid authorization code"));
+    }
+
+    // The uri could have been removed from the client since the code was issued
+    if !is_redirect_uri_registered(conn, client_id, redirect_uri) {
+        return Err(TokenError::new(400, "invalid_grant", "The redirect uri is no longer registered"));
+    }
+
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+
+    if (code_expire as u64) < since_the_epoch.as_secs() {
+        return Err(TokenError::new(400, "invalid_grant", "The authorization code expired"));
+    }
+
+    if let Some(code_challenge) = code_challenge {
+        if code_verifier.is_none() || !verify_code_challenge(&code_challenge, &code_challenge_method.unwrap(), &code_verifier.unwrap()) {
+            return Err(TokenError::new(400, "invalid_grant", "Invalid code verifier"));
+        }
+    }
+
+    let res: AuthenticationResponse = get_access_token_user_id(conn, &grant, &config.token_secret);
+    if !res.success {
+        return Err(match res.status_code {
+            Some(400) => TokenError::new(400, "invalid_scope", "The granted scope is no longer registered"),
+            Some(401) => TokenError::new(401, "invalid_client", "Client authentication failed"),
+            Some(403) => TokenError::new(400, "invalid_grant", "The user can't sign in to this client right now"),
+            _ => TokenError::server_error()
+        });
+    }
+
+    // Only an OpenID Connect request (openid scope) gets an ID token, plain OAuth clients just get the access token
+    let scopes: Vec<String> = scope_to_vec(conn, grant.scope).unwrap_or_default();
+    let id_token: Option<String> = if scopes.contains(&String::from("openid")) {
+        match create_id_token(conn, config, &grant, nonce, auth_time) {
+            Some(id_token) => Some(id_token),
+            None => return Err(TokenError::server_error())
+        }
+    } else {
+        None
+    };
+
+    return Ok(TokenResponse{
+        access_token: res.access_token.unwrap_or_default(),
+        token_type: String::from("Bearer"),
+        expires_in: res.expires_in.unwrap_or_default(),
+        refresh_token: res.refresh_token,
+        scope: scopes.join(" "),
+        id_token
+    });
+}
+
+// Client credentials grant (RFC 6749 section 4.4), the token represents the client itself.
+// Without a requested scope the token gets every scope the client is allowed to request.
+fn issue_client_token(conn: &mut postgres::Client, client_id: &String, scope: Option<String>, token_secret: &String) -> Result<TokenResponse, TokenError>{
+    let allowed_scope: u64 = match get_client_allowed_scope(conn, client_id) {
+        Some(allowed_scope) => allowed_scope,
+        None => return Err(TokenError::new(401, "invalid_client", "Client authentication failed"))
+    };
+    if allowed_scope == 0 {
+        return Err(TokenError::new(400, "invalid_scope", "The client may not request any scope"));
+    }
+
+    let scope: u64 = match scope {
+        Some(scope) => match parse_scope(conn, &scope) {
+            Some(scope) => scope,
+            None => return Err(TokenError::new(400, "invalid_scope", "The requested scope is invalid"))
+        },
+        None => allowed_scope
+    };
+    if scope_to_vec(conn, scope).is_none() || !scope_includes(allowed_scope, scope) {
+        return Err(TokenError::new(400, "invalid_scope", "The client may not request this scope"));
+    }
+
+    let access_token: String = random_bytes();
+    if !store_client_token(conn, client_id, &access_token, scope, token_secret) {
+        return Err(TokenError::server_error());
+    }
+
+    return Ok(TokenResponse{
+        access_token,
+        token_type: String::from("Bearer"),
+        expires_in: CLIENT_TOKEN_DURATION,
+        refresh_token: None,
+        scope: scope_string(conn, scope),
+        id_token: None
+    });
+}
+
+// 200 - claims, 401 - invalid, revoked or expired token, 403 - token issued to a client for itself, 500 - internal error
+fn get_user_info(conn: &mut postgres::Client, access_token: &String, token_secret: &String) -> (u16, String){
+    let token_info = get_token_info(conn, access_token, token_secret);
+
+    if token_info.is_none() {
+        return (401, String::from("{\"success\": false, \"status_code\": 401, \"error\": \"invalid token\"}"));
+    }
+    let token_info = token_info.unwrap();
+
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+
+    if token_info.expiration < since_the_epoch.as_secs() {
+        return (401, String::from("{\"success\": false, \"status_code\": 401, \"error\": \"token expired\"}"));
+    }
+
+    if token_info.client_token {
+        return (403, String::from("{\"success\": false, \"status_code\": 403, \"error\": \"token does not belong to a user\"}"));
+    }
+
+    return match get_user_claims(conn, &token_info.user_id, token_info.scope) {
+        Some(claims) => (200, claims.to_string()),
+        None => (500, String::from("{\"success\": false, \"status_code\": 500, \"error\": \"internal server error\"}"))
+    };
+}
+
+// Token introspection response (RFC 7662), only access tokens can be introspected so far
+fn introspect_token(conn: &mut postgres::Client, token: &String, token_secret: &String) -> Value{
+    let token_info = get_token_info(conn, token, token_secret);
+
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+
+    return match token_info {
+        Some(token_info) if token_info.expiration >= since_the_epoch.as_secs() => json!({
+            "active": true,
+            "scope": scope_to_vec(conn, token_info.scope).unwrap_or_default().join(" "),
+            "client_id": token_info.client_id,
+            "sub": token_info.user_id,
+            "exp": token_info.expiration,
+            "iat": token_info.issued,
+            "token_type": "Bearer"
+        }),
+        _ => json!({
+            "active": false
+        })
+    };
+}
+
+// Revokes an access or refresh token (RFC 7009), revoking a refresh token also revokes the access token issued with it.
+// Clients can only revoke their own tokens. Returns false only on database errors, unknown tokens are not an error.
+fn revoke_token(conn: &mut postgres::Client, client_id: &String, token: &String, token_type_hint: Option<String>, token_secret: &String) -> bool{
+    let refresh_first: bool = token_type_hint.unwrap_or_default() == "refresh_token";
+    let token_hash: String = hash_token(token, token_secret);
+
+    for refresh in [refresh_first, !refresh_first] {
+        let result = if refresh {
+            conn.execute("UPDATE tokens SET refresh_token_revoked = true, access_token_revoked = true WHERE refresh_token=$1 AND client_id=$2", &[&token_hash, client_id])
+        } else {
+            conn.execute("UPDATE tokens SET access_token_revoked = true WHERE access_token=$1 AND client_id=$2", &[&token_hash, client_id])
+        };
+
+        match result {
+            Ok(0) => continue,
+            Ok(_) => return true,
+            Err(_e) => return false
+        }
+    }
+
+    return true;
+}
+
+// Sends an error back to the client (RFC 6749 section 4.1.2.1). Only for clients and redirect uris that were already
+// validated, errors about those are shown on a local error page instead.
+pub fn error_redirect(redirect_uri: &str, error: &str, error_description: &str, state: &Option<String>) -> Redirect {
+    let mut error_uri: String = String::from(redirect_uri);
+    error_uri += if redirect_uri.contains('?') { "&" } else { "?" };
+    error_uri += &format!("error={}&error_description={}", error, encode(error_description));
+    if let Some(state) = state {
+        error_uri += &format!("&state={}", encode(state));
+    }
+    return Redirect::to(error_uri);
+}
+
+// Local error page for requests whose client or redirect uri can't be trusted
+fn error_page(error: &str) -> Template {
+    let mut context: HashMap<&str, &str> = HashMap::new();
+    context.insert("error", error);
+    return Template::render("error", context);
+}
+
+// Returns the error to show on a local error page if the client or the redirect uri is unknown
+pub async fn validate_client_redirect(conn: &UsersDBConnection, client_id: &str, redirect_uri: &str) -> Option<&'static str> {
+    let client_id_: String = String::from(client_id);
+    let redirect_uri_: String = String::from(redirect_uri);
+    return conn.run(move |c| {
+        if !get_client_info(c, &client_id_).success {
+            return Some("Invalid client id");
+        }
+        if !is_redirect_uri_registered(c, &client_id_, &redirect_uri_) {
+            return Some("Invalid redirect uri");
+        }
+        return None;
+    }).await;
+}
+
+#[get("/authorize?<query..>")]
+async fn authorize_page(conn: UsersDBConnection, cookies: &CookieJar<'_>, user_agent: UserAgent, config: &State<OAuthConfig>, query: AuthorizationQuery) -> Result<Redirect, Template> {
+    let AuthorizationQuery{response_type, client_id, scope, redirect_uri, code_challenge, code_challenge_method, state, nonce, prompt} = query;
+
+    // Unknown clients and redirect uris get an error page, redirecting would send the user somewhere nobody registered
+    if let Some(error) = validate_client_redirect(&conn, &client_id, &redirect_uri).await {
+        return Err(error_page(error));
+    }
+
+    if response_type != "code" {
+        return Ok(error_redirect(&redirect_uri, "unsupported_response_type", "Only the code response type is supported", &state));
+    }
+
+    let scope: u64 = match conn.run(move |c| {
+        return parse_scope(c, &scope);
+    }).await {
+        Some(scope) => scope,
+        None => return Ok(error_redirect(&redirect_uri, "invalid_scope", "The requested scope is invalid", &state))
+    };
+
+    // A malformed challenge is rejected before the user logs in for a code that couldn't be issued anyway
+    let client_id_: String = client_id.clone();
+    let public: bool = conn.run(move |c| {
+        return get_client_info(c, &client_id_).public;
+    }).await;
+    if let Err(error) = check_code_challenge(public, &code_challenge, &code_challenge_method) {
+        return Ok(error_redirect(&redirect_uri, "invalid_request", error, &state));
+    }
+
+    // prompt=none asks for a code without showing any page, which only works if the user is already logged in
+    if prompt.as_deref() == Some("none") {
+        let (user_id, amr): (String, Vec<String>) = match get_session(&conn, cookies).await {
+            Some(session) => session,
+            None => return Ok(error_redirect(&redirect_uri, "login_required", "The user is not logged in", &state))
+        };
+
+        let user_id_: String = user_id.clone();
+        let client_id_: String = client_id.clone();
+        let consent_given: bool = conn.run(move |c| {
+            return has_consent(c, &user_id_, &client_id_, scope);
+        }).await;
+        if !consent_given {
+            return Ok(error_redirect(&redirect_uri, "consent_required", "The user has not approved the requested scope", &state));
+        }
+
+        let redirect_uri_: String = redirect_uri.clone();
+        let token_secret: String = config.token_secret.clone();
+        let res: AuthorizationResponse = conn.run(move |c| {
+            let authorization = AuthorizationGrant{
+                grant: TokenGrant{user_id, client_id, scope, user_agent: user_agent.0, amr},
+                redirect_uri: redirect_uri_,
+                code_challenge,
+                code_challenge_method,
+                nonce
+            };
+            return create_authorization_code(c, authorization, &token_secret);
+        }).await;
+
+        if !res.success {
+            return Ok(error_redirect(&redirect_uri, "invalid_request", &res.error.unwrap_or_default(), &state));
+        }
+
+        let mut code_uri: String = redirect_uri.clone();
+        code_uri += if redirect_uri.contains('?') { "&" } else { "?" };
+        code_uri += &format!("code={}", encode(&res.code.unwrap()));
+        if let Some(state) = state {
+            code_uri += &format!("&state={}", encode(&state));
+        }
+        return Ok(Redirect::to(code_uri));
+    }
+
+    let mut login_uri: String = format!("/login?client_id={}&scope={}&redirect_uri={}", encode(&client_id), scope, encode(&redirect_uri));
+    if let Some(code_challenge) = code_challenge {
+        login_uri += &format!("&code_challenge={}", encode(&code_challenge));
+    }
+    if let Some(code_challenge_method) = code_challenge_method {
+        login_uri += &format!("&code_challenge_method={}", encode(&code_challenge_method));
+    }
+    if let Some(state) = state {
+        login_uri += &format!("&state={}", encode(&state));
+    }
+    if let Some(nonce) = nonce {
+        login_uri += &format!("&nonce={}", encode(&nonce));
+    }
+    return Ok(Redirect::to(login_uri));
+}
+
+// The user declined to log in to the client on the login page
+#[get("/authorize/cancel?<client_id>&<redirect_uri>&<state>")]
+async fn authorize_cancel(conn: UsersDBConnection, client_id: String, redirect_uri: String, state: Option<String>) -> Result<Redirect, Template> {
+    if let Some(error) = validate_client_redirect(&conn, &client_id, &redirect_uri).await {
+        return Err(error_page(error));
+    }
+    return Ok(error_redirect(&redirect_uri, "access_denied", "The user denied the request", &state));
+}
+
+#[post("/authorize", format = "json", data = "<input>", rank = 1)]
+async fn authorize(conn: UsersDBConnection, cookies: &CookieJar<'_>, user_agent: UserAgent, config: &State<OAuthConfig>, input: Json<AuthorizationRequest>) -> (Status, (ContentType, String)) {
+    let req = input.into_inner();
+
+    if req.client_id.is_none() || req.redirect_uri.is_none() {
+        return (Status::BadRequest, (ContentType::JSON, String::from("{\"status_code\": 400, \"error\": \"invalid request\", \"success\": false}")));
+    }
+
+    let client_id: String = req.client_id.unwrap();
+    let redirect_uri: String = req.redirect_uri.unwrap();
+    let requested_scope: u64 = req.scope;
+    // The number is a bitmask, 0 is stored as the scopes it stands for right now
+    let scope: u64 = match conn.run(move |c| {
+        return parse_scope(c, &requested_scope.to_string());
+    }).await {
+        Some(scope) => scope,
+        None => return (Status::BadRequest, (ContentType::JSON, String::from("{\"status_code\": 400, \"error\": \"invalid scope\", \"success\": false}")))
+    };
+    let code_challenge: Option<String> = req.code_challenge;
+    let code_challenge_method: Option<String> = req.code_challenge_method;
+    let nonce: Option<String> = req.nonce;
+    // The state is only handed back so the client can match the redirect to the request it made
+    let state: Option<String> = req.state;
+
+    // Log in with the username and password if they were sent, otherwise with the remember-me cookies
+    let password_login: bool = req.username.is_some() && req.password.is_some();
+    let (user_id, session_amr): (String, Vec<String>) = if password_login {
+        let username: String = req.username.unwrap();
+        let password: String = req.password.unwrap();
+
+        let user_id_res = conn.run(move |c| {
+            return get_user_id_by_credentials(c, &username, &password);
+        }).await;
+
+        if user_id_res.is_none() {
+            return (Status::Unauthorized, (ContentType::JSON, String::from("{\"status_code\": 401, \"error\": \"invalid credentials\", \"success\": false}")));
+        }
+        (user_id_res.unwrap(), Vec::new())
+    } else {
+        match get_session(&conn, cookies).await {
+            Some(session) => session,
+            None => return (Status::Unauthorized, (ContentType::JSON, String::from("{\"status_code\": 401, \"error\": \"invalid credentials\", \"success\": false}")))
+        }
+    };
+
+    // Consent is asked for before the second factor, a TOTP code can only be used once and would be gone by the time
+    // the request is sent again with the consent
+    let consent: bool = req.consent.unwrap_or(false);
+    let user_id_: String = user_id.clone();
+    let client_id_: String = client_id.clone();
+    let consent_given: bool = conn.run(move |c| {
+        return has_consent(c, &user_id_, &client_id_, scope);
+    }).await;
+
+    if !consent_given && !consent {
+        return (Status::Forbidden, (ContentType::JSON, String::from("{\"status_code\": 403, \"error\": \"consent required\", \"consent_required\": true, \"success\": false}")));
+    }
+
+    let amr: Vec<String> = if password_login {
+        let user_id_: String = user_id.clone();
+        let otp: Option<String> = req.otp;
+        let second_factor: u8 = conn.run(move |c| {
+            return check_second_factor(c, &user_id_, &otp);
+        }).await;
+
+        if second_factor == 2 {
+            return (Status::Unauthorized, (ContentType::JSON, String::from("{\"status_code\": 401, \"error\": \"two factor code required\", \"two_factor_required\": true, \"success\": false}")));
+        } else if second_factor == 3 {
+            return (Status::Unauthorized, (ContentType::JSON, String::from("{\"status_code\": 401, \"error\": \"invalid two factor code\", \"two_factor_required\": true, \"success\": false}")));
+        } else if second_factor == 4 {
+            return (Status::TooManyRequests, (ContentType::JSON, String::from("{\"status_code\": 429, \"error\": \"too many wrong two factor codes, try again later\", \"two_factor_required\": true, \"success\": false}")));
+        }
+        login_amr(second_factor)
+    } else {
+        session_amr
+    };
+
+    if !consent_given {
+        let user_id_: String = user_id.clone();
+        let client_id_: String = client_id.clone();
+        let granted: bool = conn.run(move |c| {
+            return grant_consent(c, &user_id_, &client_id_, scope);
+        }).await;
+
+        if !granted {
+            return (Status::InternalServerError, (ContentType::JSON, String::from("{\"status_code\": 500, \"error\": \"internal server error\", \"success\": false}")));
+        }
+    }
+
+    // The session is only remembered once the login went through, asking for consent sends the password again
+    if password_login && req.remember && !start_session(&conn, cookies, user_id.clone(), amr.clone()).await {
+        return (Status::InternalServerError, (ContentType::JSON, String::from("{\"status_code\": 500, \"error\": \"internal server error\", \"success\": false}")));
+    }
+
+    let token_secret: String = config.token_secret.clone();
+    let mut res: AuthorizationResponse = conn.run(move |c| {
+        let authorization = AuthorizationGrant{
+            grant: TokenGrant{user_id, client_id, scope, user_agent: user_agent.0, amr},
+            redirect_uri,
+            code_challenge,
+            code_challenge_method,
+            nonce
+        };
+        return create_authorization_code(c, authorization, &token_secret);
+    }).await;
+    if res.success {
+        res.state = state;
+    }
+
+    let res_json: String = rocket::serde::json::serde_json::to_string_pretty(&res).unwrap();
+    return (Status::from_code(res.status_code.unwrap()).unwrap(), (ContentType::JSON, res_json));
+}
+
+#[post("/token", data = "<input>")]
+async fn token(conn: UsersDBConnection, config: &State<OAuthConfig>, basic: Option<BasicCredentials>, input: Form<TokenRequest>) -> OAuthResponse {
+    let req = input.into_inner();
+    let config: OAuthConfig = config.inner().clone();
+    let grant_type: String = req.grant_type.unwrap_or_default();
+
+    let basic_used: bool = basic.is_some();
+    let auth = ClientAuthentication{
+        basic,
+        client_id: req.client_id,
+        client_secret: req.client_secret,
+        client_assertion_type: req.client_assertion_type,
+        client_assertion: req.client_assertion
+    };
+    let config_: OAuthConfig = config.clone();
+    let (client_id, auth_method): (String, String) = match conn.run(move |c| {
+        return authenticate_client(c, &config_, auth);
+    }).await {
+        Some(client) => client,
+        None => return OAuthResponse::invalid_client(basic_used)
+    };
+
+    let res: Result<TokenResponse, TokenError> = if grant_type == "authorization_code" {
+        if req.code.is_none() || req.redirect_uri.is_none() {
+            return OAuthResponse::error(TokenError::new(400, "invalid_request", "code and redirect_uri are required"));
+        }
+        let code: String = req.code.unwrap();
+        let redirect_uri: String = req.redirect_uri.unwrap();
+        let code_verifier: Option<String> = req.code_verifier;
+
+        conn.run(move |c| {
+            return exchange_authorization_code(c, &config, &code, &client_id, &redirect_uri, code_verifier);
+        }).await
+    } else if grant_type == "refresh_token" {
+        if req.refresh_token.is_none() {
+            return OAuthResponse::error(TokenError::new(400, "invalid_request", "refresh_token is required"));
+        }
+        let refresh_token: String = req.refresh_token.unwrap();
+
+        conn.run(move |c| {
+            return rotate_refresh_token(c, &client_id, &refresh_token, &config.token_secret);
+        }).await
+    } else if grant_type == "client_credentials" {
+        // Public clients can't prove who they are, so they can't get a token for themselves
+        if auth_method == "none" {
+            return OAuthResponse::error(TokenError::new(400, "unauthorized_client", "Public clients can't use the client credentials grant"));
+        }
+        let scope: Option<String> = req.scope;
+
+        conn.run(move |c| {
+            return issue_client_token(c, &client_id, scope, &config.token_secret);
+        }).await
+    } else {
+        Err(TokenError::new(400, "unsupported_grant_type", "Supported grant types are authorization_code, refresh_token and client_credentials"))
+    };
+
+    return OAuthResponse::token(res);
+}
+
+#[post("/introspect", data = "<input>")]
+async fn introspect(conn: UsersDBConnection, config: &State<OAuthConfig>, basic: Option<BasicCredentials>, input: Form<IntrospectionRequest>) -> OAuthResponse {
+    let req = input.into_inner();
+    let config: OAuthConfig = config.inner().clone();
+
+    if req.token.is_none() {
+        return OAuthResponse::error(TokenError::new(400, "invalid_request", "token is required"));
+    }
+    let token: String = req.token.unwrap();
+
+    let basic_used: bool = basic.is_some();
+    let auth = ClientAuthentication{
+        basic,
+        client_id: req.client_id,
+        client_secret: req.client_secret,
+        client_assertion_type: req.client_assertion_type,
+        client_assertion: req.client_assertion
+    };
+
+    let res: Option<Value> = conn.run(move |c| {
+        // Only confidential clients may introspect tokens, the response reveals who a token belongs to
+        return match authenticate_client(c, &config, auth) {
+            Some((_client_id, auth_method)) if auth_method != "none" => Some(introspect_token(c, &token, &config.token_secret)),
+            _ => None
+        };
+    }).await;
+
+    return match res {
+        Some(res) => OAuthResponse::ok(res),
+        None => OAuthResponse::invalid_client(basic_used)
+    };
+}
+
+#[post("/revoke", data = "<input>")]
+async fn revoke(conn: UsersDBConnection, config: &State<OAuthConfig>, basic: Option<BasicCredentials>, input: Form<RevocationRequest>) -> OAuthResponse {
+    let req = input.into_inner();
+    let config: OAuthConfig = config.inner().clone();
+
+    if req.token.is_none() {
+        return OAuthResponse::error(TokenError::new(400, "invalid_request", "token is required"));
+    }
+    let token: String = req.token.unwrap();
+    let token_type_hint: Option<String> = req.token_type_hint;
+
+    let basic_used: bool = basic.is_some();
+    let auth = ClientAuthentication{
+        basic,
+        client_id: req.client_id,
+        client_secret: req.client_secret,
+        client_assertion_type: req.client_assertion_type,
+        client_assertion: req.client_assertion
+    };
+
+    // 200 - revoked or unknown token, 401 - invalid client credentials, 500 - internal error
+    let res: u16 = conn.run(move |c| {
+        let client_id: String = match authenticate_client(c, &config, auth) {
+            Some((client_id, _auth_method)) => client_id,
+            None => return 401
+        };
+        return if revoke_token(c, &client_id, &token, token_type_hint, &config.token_secret) { 200 } else { 500 };
+    }).await;
+
+    return match res {
+        200 => OAuthResponse::ok(json!({})),
+        401 => OAuthResponse::invalid_client(basic_used),
+        _ => OAuthResponse::error(TokenError::server_error())
+    };
+}
+
+async fn user_info(conn: UsersDBConnection, config: &State<OAuthConfig>, access_token: Option<BearerToken>) -> (Status, (ContentType, String)) {
+    if access_token.is_none() {
+        return (Status::Unauthorized, (ContentType::JSON, String::from("{\"success\": false, \"status_code\": 401, \"error\": \"invalid token\"}")));
+    }
+    let access_token: String = access_token.unwrap().0;
+    let token_secret: String = config.token_secret.clone();
+
+    let res = conn.run(move |c| {
+        return get_user_info(c, &access_token, &token_secret);
+    }).await;
+
+    return (Status::from_code(res.0).unwrap(), (ContentType::JSON, res.1));
+}
+
+#[get("/userinfo")]
+async fn user_info_get(conn: UsersDBConnection, config: &State<OAuthConfig>, access_token: Option<BearerToken>) -> (Status, (ContentType, String)) {
+    return user_info(conn, config, access_token).await;
+}
+
+#[post("/userinfo")]
+async fn user_info_post(conn: UsersDBConnection, config: &State<OAuthConfig>, access_token: Option<BearerToken>) -> (Status, (ContentType, String)) {
+    return user_info(conn, config, access_token).await;
+}
+
+// Manages the OAuth configuration. There is no default token_secret outside the debug profile, so a release build
+// refuses to launch until one is set instead of hashing tokens with a key everyone can read. An unknown
+// signing_algorithm is refused as well, it would only show up once the first ID token can't be signed.
+pub fn config() -> rocket::fairing::AdHoc {
+    return rocket::fairing::AdHoc::try_on_ignite("OAuth config", |rocket| async {
+        return match rocket.figment().extract::<OAuthConfig>() {
+            Ok(config) if config.token_secret.is_empty() => {
+                println!("token_secret must not be empty");
+                Err(rocket)
+            },
+            Ok(config) if !SIGNING_ALGORITHMS.contains(&config.signing_algorithm.as_str()) => {
+                println!("signing_algorithm must be one of {}, not {}", SIGNING_ALGORITHMS.join(", "), config.signing_algorithm);
+                Err(rocket)
+            },
+            Ok(config) => Ok(rocket.manage(config)),
+            Err(e) => {
+                println!("Invalid OAuth configuration, release builds need token_secret set with ROCKET_TOKEN_SECRET: {}", e);
+                Err(rocket)
+            }
+        };
+    });
+}
+
+pub fn stage() -> Vec<rocket::Route> {
+    routes![authorize_page, authorize_cancel, authorize, token, introspect, revoke, user_info_get, user_info_post]
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rocket::serde::json::serde_json::json;
+
+    use super::{check_code_challenge, client_assertion_expire, verify_code_challenge};
+
+    // RFC 7636 Appendix B
+    const VERIFIER: &str = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+    const S256_CHALLENGE: &str = "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM";
+
+    #[test]
+    fn s256_matches_rfc_vector() {
+        assert!(verify_code_challenge(&String::from(S256_CHALLENGE), &String::from("S256"), &String::from(VERIFIER)));
+    }
+
+    #[test]
+    fn s256_rejects_other_verifier() {
+        let other: String = VERIFIER.replace('d', "e");
+        assert!(!verify_code_challenge(&String::from(S256_CHALLENGE), &String::from("S256"), &other));
+    }
+
+    #[test]
+    fn plain_compares_verifier_and_challenge() {
+        assert!(verify_code_challenge(&String::from(VERIFIER), &String::from("plain"), &String::from(VERIFIER)));
+        assert!(!verify_code_challenge(&String::from(S256_CHALLENGE), &String::from("plain"), &String::from(VERIFIER)));
+    }
+
+    #[test]
+    fn rejects_verifier_of_invalid_length() {
+        let short: String = String::from(&VERIFIER[..42]);
+        assert!(!verify_code_challenge(&short, &String::from("plain"), &short));
+        let long: String = "a".repeat(129);
+        assert!(!verify_code_challenge(&long, &String::from("plain"), &long));
+    }
+
+    #[test]
+    fn rejects_unknown_method() {
+        assert!(!verify_code_challenge(&String::from(VERIFIER), &String::from("S512"), &String::from(VERIFIER)));
+    }
+
+    #[test]
+    fn rejects_verifier_with_reserved_characters() {
+        let verifier: String = VERIFIER.replace('d', "+");
+        assert!(!verify_code_challenge(&verifier, &String::from("plain"), &verifier));
+    }
+
+    fn check(public: bool, code_challenge: Option<&str>, code_challenge_method: Option<&str>) -> Result<Option<String>, &'static str> {
+        return check_code_challenge(public, &code_challenge.map(String::from), &code_challenge_method.map(String::from));
+    }
+
+    #[test]
+    fn public_clients_need_s256() {
+        assert_eq!(check(true, Some(S256_CHALLENGE), Some("S256")), Ok(Some(String::from("S256"))));
+        assert!(check(true, Some(VERIFIER), Some("plain")).is_err());
+        assert!(check(true, Some(VERIFIER), None).is_err());
+        assert!(check(true, None, None).is_err());
+    }
+
+    #[test]
+    fn confidential_clients_default_to_plain() {
+        assert_eq!(check(false, None, None), Ok(None));
+        assert_eq!(check(false, Some(VERIFIER), None), Ok(Some(String::from("plain"))));
+        assert!(check(false, Some(VERIFIER), Some("S512")).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_challenges() {
+        assert!(check(false, Some(&VERIFIER[..42]), Some("plain")).is_err());
+        assert!(check(false, Some(&"a".repeat(129)), Some("plain")).is_err());
+        assert!(check(true, Some(&S256_CHALLENGE.replace('E', "=")), Some("S256")).is_err());
+    }
+
+    const NOW: i64 = 1_700_000_000;
+
+    #[test]
+    fn accepts_short_lived_assertions() {
+        assert_eq!(client_assertion_expire(&json!({"exp": NOW + 60}), NOW), Some(NOW + 60));
+        assert_eq!(client_assertion_expire(&json!({"exp": NOW + 60, "iat": NOW - 30, "nbf": NOW - 30}), NOW), Some(NOW + 60));
+        assert_eq!(client_assertion_expire(&json!({"exp": NOW + 300, "iat": NOW + 10}), NOW), Some(NOW + 300));
+    }
+
+    #[test]
+    fn rejects_expired_assertions() {
+        assert_eq!(client_assertion_expire(&json!({"exp": NOW - 1}), NOW), None);
+        assert_eq!(client_assertion_expire(&json!({}), NOW), None);
+    }
+
+    #[test]
+    fn rejects_long_lived_assertions() {
+        assert_eq!(client_assertion_expire(&json!({"exp": NOW + 301}), NOW), None);
+        assert_eq!(client_assertion_expire(&json!({"exp": NOW + 60, "iat": NOW - 600}), NOW), None);
+    }
+
+    #[test]
+    fn rejects_assertions_from_the_future() {
+        assert_eq!(client_assertion_expire(&json!({"exp": NOW + 120, "iat": NOW + 90}), NOW), None);
+        assert_eq!(client_assertion_expire(&json!({"exp": NOW + 120, "nbf": NOW + 90}), NOW), None);
+        assert_eq!(client_assertion_expire(&json!({"exp": NOW + 120, "nbf": "soon"}), NOW), None);
+    }
+}
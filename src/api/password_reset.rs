@@ -0,0 +1,174 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rocket::http::{ContentType, Status};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::serde::json::Json;
+use rocket::State;
+use rocket_sync_db_pools::postgres;
+use urlencoding::encode;
+
+use crate::api::events::record_security_event;
+use crate::api::oauth::OAuthConfig;
+use crate::api::users::{hash_token, random_bytes, random_bytes_l, ARGON_CONFIG};
+use crate::db::UsersDBConnection;
+use crate::mail::Mailer;
+
+//Duration in seconds (one hour)
+const PASSWORD_RESET_DURATION: u64 = 60*60;
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct PasswordResetRequest{
+    email: Option<String>
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct PasswordResetConfirmation{
+    token: Option<String>,
+    password: Option<String>
+}
+
+// Creates a reset token for every account with the email address and returns the tokens to send.
+// Only the hash of the token is stored, and creating a new token replaces the unused ones.
+fn create_password_resets(conn: &mut postgres::Client, email: &String, token_secret: &String) -> Vec<String> {
+    let users = match conn.query("SELECT id FROM users WHERE email=$1", &[email]) {
+        Ok(users) => users,
+        Err(_e) => return Vec::new()
+    };
+
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    let created: i64 = since_the_epoch.as_secs() as i64;
+    let expire: i64 = (since_the_epoch.as_secs() + PASSWORD_RESET_DURATION) as i64;
+
+    let mut tokens: Vec<String> = Vec::new();
+    for user in users {
+        let user_id: String = user.get(0);
+        let token: String = random_bytes();
+
+        if conn.execute("DELETE FROM password_resets WHERE user_id=$1", &[&user_id]).is_err() {
+            continue;
+        }
+        let result = conn.execute("INSERT INTO password_resets (token_hash, user_id, expire, created) VALUES ($1, $2, $3, $4)",
+                                  &[&hash_token(&token, token_secret), &user_id, &expire, &created]);
+        if result.is_ok() {
+            record_security_event(conn, &user_id, None, "password_reset_requested", "a password reset link was sent to the email address");
+            tokens.push(token);
+        }
+    }
+
+    return tokens;
+}
+
+// 0 - password changed, 1 - invalid or expired token, 2 - password empty, 3 - internal error
+// The token is used up even if the reset fails afterwards. Every remember-me session and OAuth token of the user is
+// revoked, whoever knew the old password shouldn't stay logged in.
+fn reset_password(conn: &mut postgres::Client, token: &String, password: &String, token_secret: &String) -> u8 {
+    if password.is_empty() {
+        return 2;
+    }
+
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    let now: i64 = since_the_epoch.as_secs() as i64;
+
+    let reset = conn.query_opt("DELETE FROM password_resets WHERE token_hash=$1 RETURNING user_id, expire", &[&hash_token(token, token_secret)]);
+    let (user_id, expire): (String, i64) = match reset {
+        Ok(Some(row)) => (row.get(0), row.get(1)),
+        Ok(None) => return 1,
+        Err(_e) => return 3
+    };
+    if expire < now {
+        return 1;
+    }
+
+    let salt: String = random_bytes_l(32);
+    let password_hash: String = match argon2::hash_encoded(password.as_bytes(), salt.as_bytes(), &ARGON_CONFIG) {
+        Ok(password_hash) => password_hash,
+        Err(_e) => return 3
+    };
+
+    let mut transaction = match conn.transaction() {
+        Ok(transaction) => transaction,
+        Err(_e) => return 3
+    };
+
+    // The link was opened from the user's inbox, so the address is verified as well
+    let updated = transaction.execute("UPDATE users SET password=$1, salt=$2, email_verified = true WHERE id=$3", &[&password_hash, &salt, &user_id]);
+    if updated.is_err() || updated.unwrap() != 1 {
+        return 3;
+    }
+    if transaction.execute("DELETE FROM login_tokens WHERE user_id=$1", &[&user_id]).is_err() {
+        return 3;
+    }
+    if transaction.execute("UPDATE tokens SET access_token_revoked = true, refresh_token_revoked = true WHERE user_id=$1", &[&user_id]).is_err() {
+        return 3;
+    }
+    // A code that wasn't exchanged yet would still get a new token set after the reset
+    if transaction.execute("DELETE FROM authorization_codes WHERE user_id=$1", &[&user_id]).is_err() {
+        return 3;
+    }
+    if transaction.execute("DELETE FROM password_resets WHERE user_id=$1", &[&user_id]).is_err() {
+        return 3;
+    }
+    if transaction.commit().is_err() {
+        return 3;
+    }
+
+    record_security_event(conn, &user_id, None, "password_reset", "the password was reset, every session and token was revoked");
+    return 0;
+}
+
+// Always answers the same way, so the endpoint can't be used to find out which addresses have an account
+#[post("/password_reset", format = "json", data = "<input>")]
+async fn request_reset(conn: UsersDBConnection, mailer: &State<Mailer>, config: &State<OAuthConfig>, input: Json<PasswordResetRequest>) -> (Status, (ContentType, String)) {
+    let email: String = match input.into_inner().email {
+        Some(email) if !email.trim().is_empty() => email.trim().to_string(),
+        _ => return (Status::BadRequest, (ContentType::JSON, String::from("{\"status_code\": 400, \"error\": \"invalid request\", \"success\": false}")))
+    };
+    let email_: String = email.clone();
+    let token_secret: String = config.token_secret.clone();
+
+    let tokens: Vec<String> = conn.run(move |c| {
+        return create_password_resets(c, &email_, &token_secret);
+    }).await;
+
+    for token in tokens {
+        let link: String = format!("{}/account/reset_password?token={}", config.issuer, encode(&token));
+        let body: String = format!("Someone asked to reset the password of your account. You can choose a new password by opening this link:\n\n{}\n\nThe link is valid for one hour and can only be used once. If you didn't ask for this, you can ignore this email and your password stays the same.", link);
+        mailer.send_in_background(&email, "Reset your password", body);
+    }
+
+    return (Status::Ok, (ContentType::JSON, String::from("{\"status_code\": 200, \"error\": null, \"success\": true}")));
+}
+
+#[post("/password_reset/confirm", format = "json", data = "<input>")]
+async fn confirm_reset(conn: UsersDBConnection, config: &State<OAuthConfig>, input: Json<PasswordResetConfirmation>) -> (Status, (ContentType, String)) {
+    let req = input.into_inner();
+    if req.token.is_none() || req.password.is_none() {
+        return (Status::BadRequest, (ContentType::JSON, String::from("{\"status_code\": 400, \"error\": \"invalid request\", \"success\": false}")));
+    }
+    let token: String = req.token.unwrap();
+    let password: String = req.password.unwrap();
+    let token_secret: String = config.token_secret.clone();
+
+    let res: u8 = conn.run(move |c| {
+        return reset_password(c, &token, &password, &token_secret);
+    }).await;
+
+    return match res {
+        0 => (Status::Ok, (ContentType::JSON, String::from("{\"status_code\": 200, \"error\": null, \"success\": true}"))),
+        1 => (Status::BadRequest, (ContentType::JSON, String::from("{\"status_code\": 400, \"error\": \"invalid or expired link\", \"success\": false}"))),
+        2 => (Status::BadRequest, (ContentType::JSON, String::from("{\"status_code\": 400, \"error\": \"password empty\", \"success\": false}"))),
+        _ => (Status::InternalServerError, (ContentType::JSON, String::from("{\"status_code\": 500, \"error\": \"internal server error\", \"success\": false}")))
+    };
+}
+
+pub fn stage() -> Vec<rocket::Route> {
+    routes![request_reset, confirm_reset]
+}
\ No newline at end of file
@@ -0,0 +1,140 @@
+use rocket::serde::{Deserialize, Serialize};
+use rocket_sync_db_pools::postgres;
+
+// A scope from the scopes table. Every scope has its own bit, so scopes can still be stored and sent as a bitmask
+// like before they had names (email - 1, profile - 2).
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct Scope{
+    pub name: String,
+    pub bit: u64,
+    pub display_name: String,
+    pub description: String
+}
+
+pub fn get_scopes(conn: &mut postgres::Client) -> Vec<Scope> {
+    let rows = match conn.query("SELECT name, bit, display_name, description FROM scopes ORDER BY bit", &[]) {
+        Ok(rows) => rows,
+        Err(_e) => return Vec::new()
+    };
+
+    let mut scopes: Vec<Scope> = Vec::new();
+    for row in rows {
+        let bit: i64 = row.get(1);
+        scopes.push(Scope{
+            name: row.get(0),
+            bit: bit as u64,
+            display_name: row.get(2),
+            description: row.get(3)
+        });
+    }
+
+    return scopes;
+}
+
+// What 0 stood for before scopes had names: every scope there was back then, email and profile.
+// Scopes registered since then have to be requested by name or bit.
+pub const LEGACY_ALL_SCOPES: u64 = 1 | 2;
+
+// Every registered scope as a bitmask
+pub fn all_scopes(conn: &mut postgres::Client) -> u64 {
+    return get_scopes(conn).iter().fold(0, |all, scope| all | scope.bit);
+}
+
+// Returns the registered scopes in the bitmask.
+// None if the bitmask is empty or contains a bit that isn't registered.
+pub fn get_scope_details(conn: &mut postgres::Client, scope: u64) -> Option<Vec<Scope>> {
+    let scopes: Vec<Scope> = get_scopes(conn);
+    let included: Vec<Scope> = scopes.into_iter().filter(|registered| scope & registered.bit != 0).collect();
+    let included_bits: u64 = included.iter().fold(0, |all, registered| all | registered.bit);
+    if included.is_empty() || included_bits != scope {
+        return None;
+    }
+
+    return Some(included);
+}
+
+// Returns the names of the scopes in the bitmask
+pub fn scope_to_vec(conn: &mut postgres::Client, scope: u64) -> Option<Vec<String>> {
+    return get_scope_details(conn, scope).map(|scopes| scopes.into_iter().map(|scope| scope.name).collect());
+}
+
+// Parses a space separated list of scope names (RFC 6749 section 3.3) or, for existing integrations, a bitmask.
+// 0 is expanded to LEGACY_ALL_SCOPES, so only explicit scopes are ever granted and stored.
+// None if a scope isn't registered.
+pub fn parse_scope(conn: &mut postgres::Client, scope: &str) -> Option<u64> {
+    if let Ok(scope_bits) = scope.trim().parse::<u64>() {
+        let scope_bits: u64 = if scope_bits == 0 { LEGACY_ALL_SCOPES } else { scope_bits };
+        return get_scope_details(conn, scope_bits).map(|_scopes| scope_bits);
+    }
+
+    let scopes: Vec<Scope> = get_scopes(conn);
+    let mut scope_bits: u64 = 0;
+    for name in scope.split_whitespace() {
+        match scopes.iter().find(|registered| registered.name == name) {
+            Some(registered) => scope_bits |= registered.bit,
+            None => return None
+        }
+    }
+
+    return if scope_bits == 0 { None } else { Some(scope_bits) };
+}
+
+// Returns true if the granted scope includes every scope of the required one. A granted 0 includes nothing,
+// a required 0 asks for LEGACY_ALL_SCOPES like it does in requests.
+pub fn scope_includes(granted: u64, required: u64) -> bool {
+    if granted == 0 {
+        return false;
+    }
+    let required_: u64 = if required == 0 { LEGACY_ALL_SCOPES } else { required };
+    return granted & required_ == required_;
+}
+
+// Scope containing every scope of both
+pub fn scope_union(scope_a: u64, scope_b: u64) -> u64 {
+    return scope_a | scope_b;
+}
+
+// Grants stored as 0 before scopes were always expanded get LEGACY_ALL_SCOPES, the scopes 0 stood for when they
+// were granted. Returns the number of updated rows.
+pub fn migrate_unexpanded_scopes(conn: &mut postgres::Client) -> u64 {
+    let legacy: i64 = LEGACY_ALL_SCOPES as i64;
+
+    let statements: [&str; 3] = [
+        "UPDATE tokens SET scope=$1 WHERE scope = 0",
+        "UPDATE consents SET scope=$1 WHERE scope = 0",
+        "UPDATE authorization_codes SET scope=$1 WHERE scope = 0"
+    ];
+    let mut migrated: u64 = 0;
+    for statement in statements {
+        if let Ok(rows) = conn.execute(statement, &[&legacy]) {
+            migrated += rows;
+        }
+    }
+
+    return migrated;
+}
+#[cfg(test)]
+mod tests {
+    use super::{scope_includes, LEGACY_ALL_SCOPES};
+
+    #[test]
+    fn legacy_zero_only_covers_email_and_profile() {
+        assert_eq!(LEGACY_ALL_SCOPES, 1 | 2);
+        assert!(scope_includes(1 | 2, 0));
+        assert!(!scope_includes(1, 0));
+        assert!(scope_includes(1 | 2 | 4 | 8, 0));
+    }
+
+    #[test]
+    fn granted_zero_includes_nothing() {
+        assert!(!scope_includes(0, 1));
+        assert!(!scope_includes(0, 0));
+    }
+
+    #[test]
+    fn granted_scope_must_cover_required_scope() {
+        assert!(scope_includes(1 | 4, 4));
+        assert!(!scope_includes(1 | 4, 4 | 8));
+    }
+}
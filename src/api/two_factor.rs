@@ -0,0 +1,577 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use qrcode::QrCode;
+use qrcode::render::svg;
+use rand::Rng;
+use ring::hmac;
+use rocket::http::{ContentType, CookieJar, Status};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::serde::json::Json;
+use rocket::serde::json::serde_json::json;
+use rocket_sync_db_pools::postgres;
+use sha3::{Digest, Sha3_512};
+use urlencoding::encode;
+
+use super::account::verify_password;
+use super::events::record_security_event;
+use super::users::{get_session_user_id, get_username_by_id, to_hex_string};
+use crate::db::UsersDBConnection;
+
+// Name shown for the account in authenticator apps
+const TOTP_ISSUER: &str = "Aerio SSO";
+
+//Duration in seconds (RFC 6238 default)
+const TOTP_STEP: u64 = 30;
+
+const TOTP_DIGITS: u32 = 6;
+
+// Codes of the steps right before and after the current one are accepted too, the clocks are never exactly in sync
+const TOTP_ALLOWED_DRIFT: u64 = 1;
+
+// Wrong codes in a row before the second factor is locked, six digit codes can't be guessed with that few tries
+const MAX_CODE_FAILURES: i32 = 5;
+
+//Duration in seconds (five minutes), doubled for every further lockout
+const CODE_LOCKOUT_DURATION: u64 = 60*5;
+
+//Duration in seconds (one day)
+const MAX_CODE_LOCKOUT_DURATION: u64 = 60*60*24;
+
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_LENGTH: usize = 10;
+// Characters that are easily confused with each other are left out, recovery codes are typed in by hand
+const RECOVERY_CODE_CHARSET: &[u8] = b"abcdefghijkmnpqrstuvwxyz23456789";
+
+const BASE32_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct SetupRequest{
+    password: Option<String>
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct CodeRequest{
+    code: Option<String>
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct DisableRequest{
+    password: Option<String>,
+    code: Option<String>
+}
+
+// Base32 without padding (RFC 4648), the encoding authenticator apps expect for secrets
+fn base32(bytes: &[u8]) -> String {
+    let mut encoded: String = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits: u32 = 0;
+
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            encoded.push(BASE32_ALPHABET[((buffer >> (bits - 5)) & 31) as usize] as char);
+            bits -= 5;
+        }
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+
+    return encoded;
+}
+
+// TOTP code for the time step (RFC 6238 with HMAC-SHA1, which every authenticator app supports)
+fn totp(secret: &[u8], step: u64) -> u32 {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let tag = hmac::sign(&key, &step.to_be_bytes());
+    let hash: &[u8] = tag.as_ref();
+
+    let offset: usize = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary: u32 = ((hash[offset] as u32 & 0x7f) << 24)
+        | ((hash[offset + 1] as u32) << 16)
+        | ((hash[offset + 2] as u32) << 8)
+        | hash[offset + 3] as u32;
+
+    return binary % 10u32.pow(TOTP_DIGITS);
+}
+
+// Recovery codes are random enough that a plain hash is as safe as the hashed remember-me tokens
+fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code.chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>().to_lowercase();
+    let mut sha = Sha3_512::default();
+    sha.update(normalized);
+    return to_hex_string(sha.finalize().as_ref());
+}
+
+// Replaces the user's recovery codes with new ones, returns the codes to show the user once
+fn create_recovery_codes(conn: &mut postgres::Client, user_id: &String) -> Option<Vec<String>> {
+    let mut codes: Vec<String> = Vec::new();
+    for _i in 0..RECOVERY_CODE_COUNT {
+        let code: String = (0..RECOVERY_CODE_LENGTH)
+            .map(|_| {
+                let idx = rand::thread_rng().gen_range(0..RECOVERY_CODE_CHARSET.len());
+                RECOVERY_CODE_CHARSET[idx] as char
+            })
+            .collect();
+        codes.push(format!("{}-{}", &code[..RECOVERY_CODE_LENGTH / 2], &code[RECOVERY_CODE_LENGTH / 2..]));
+    }
+
+    let mut transaction = match conn.transaction() {
+        Ok(transaction) => transaction,
+        Err(_e) => return None
+    };
+    if transaction.execute("DELETE FROM recovery_codes WHERE user_id=$1", &[user_id]).is_err() {
+        return None;
+    }
+    for code in &codes {
+        if transaction.execute("INSERT INTO recovery_codes (user_id, code_hash) VALUES ($1, $2)", &[user_id, &hash_recovery_code(code)]).is_err() {
+            return None;
+        }
+    }
+    if transaction.commit().is_err() {
+        return None;
+    }
+
+    return Some(codes);
+}
+
+pub fn is_two_factor_enabled(conn: &mut postgres::Client, user_id: &String) -> bool {
+    return match conn.query_opt("SELECT totp_enabled FROM users WHERE id=$1", &[user_id]) {
+        Ok(Some(row)) => row.get(0),
+        _ => false
+    };
+}
+
+// Checks a TOTP code against the user's secret, enabled or not. A code can only be used once, so every step up to
+// the one that matched is used up.
+fn verify_totp(conn: &mut postgres::Client, user_id: &String, code: &str) -> bool {
+    let code: &str = code.trim();
+    if code.len() != TOTP_DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return false;
+    }
+    let code: u32 = match code.parse::<u32>() {
+        Ok(code) => code,
+        Err(_e) => return false
+    };
+
+    let (secret, last_step): (Vec<u8>, Option<i64>) = match conn.query_opt("SELECT totp_secret, totp_last_step FROM users WHERE id=$1 AND totp_secret IS NOT NULL", &[user_id]) {
+        Ok(Some(row)) => (row.get(0), row.get(1)),
+        _ => return false
+    };
+
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    let current_step: u64 = since_the_epoch.as_secs() / TOTP_STEP;
+
+    for step in (current_step - TOTP_ALLOWED_DRIFT)..=(current_step + TOTP_ALLOWED_DRIFT) {
+        let step_: i64 = step as i64;
+        if last_step.is_some() && last_step.unwrap() >= step_ {
+            continue;
+        }
+        if totp(&secret, step) != code {
+            continue;
+        }
+
+        let updated = conn.execute("UPDATE users SET totp_last_step=$1 WHERE id=$2 AND (totp_last_step IS NULL OR totp_last_step < $1)", &[&step_, user_id]);
+        return matches!(updated, Ok(1));
+    }
+
+    return false;
+}
+
+// Returns true while the user is locked out after too many wrong codes
+fn is_second_factor_locked(conn: &mut postgres::Client, user_id: &String) -> bool {
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    let now: i64 = since_the_epoch.as_secs() as i64;
+
+    return match conn.query_opt("SELECT totp_locked_until FROM users WHERE id=$1", &[user_id]) {
+        Ok(Some(row)) => row.get::<usize, Option<i64>>(0).is_some_and(|locked_until| locked_until > now),
+        // A failed lookup counts as locked, the code isn't checked without knowing
+        _ => true
+    };
+}
+
+// Counts a wrong code. Every MAX_CODE_FAILURES wrong codes in a row lock the second factor, each lockout twice as long
+// as the one before, until a right code resets the count.
+fn record_code_failure(conn: &mut postgres::Client, user_id: &String) {
+    let failures: i32 = match conn.query_one("UPDATE users SET totp_failures = totp_failures + 1 WHERE id=$1 RETURNING totp_failures", &[user_id]) {
+        Ok(row) => row.get(0),
+        Err(_e) => return
+    };
+    if failures % MAX_CODE_FAILURES != 0 {
+        return;
+    }
+
+    let lockouts: u32 = (failures / MAX_CODE_FAILURES) as u32;
+    let duration: u64 = CODE_LOCKOUT_DURATION.saturating_mul(2u64.saturating_pow(lockouts - 1)).min(MAX_CODE_LOCKOUT_DURATION);
+
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    let locked_until: i64 = (since_the_epoch.as_secs() + duration) as i64;
+
+    if conn.execute("UPDATE users SET totp_locked_until=$1 WHERE id=$2", &[&locked_until, user_id]).is_ok() {
+        record_security_event(conn, user_id, None, "two_factor_locked", &format!("{} wrong codes in a row, codes are not accepted for {} seconds", failures, duration));
+    }
+}
+
+fn reset_code_failures(conn: &mut postgres::Client, user_id: &String) {
+    let _ = conn.execute("UPDATE users SET totp_failures = 0, totp_locked_until = NULL WHERE id=$1 AND totp_failures > 0", &[user_id]);
+}
+
+fn use_recovery_code(conn: &mut postgres::Client, user_id: &String, code: &str) -> bool {
+    let used = conn.execute("DELETE FROM recovery_codes WHERE user_id=$1 AND code_hash=$2", &[user_id, &hash_recovery_code(code)]);
+    if !matches!(used, Ok(1)) {
+        return false;
+    }
+
+    record_security_event(conn, user_id, None, "recovery_code_used", "a recovery code was used instead of a TOTP code");
+    return true;
+}
+
+// Returns status code (u8) for a login that already checked the password
+// Status codes are: 0 - the user has no second factor, 1 - second factor verified, 2 - code required, 3 - wrong code,
+// 4 - locked after too many wrong codes. The code can be a TOTP code or one of the recovery codes.
+pub fn check_second_factor(conn: &mut postgres::Client, user_id: &String, code: &Option<String>) -> u8 {
+    if !is_two_factor_enabled(conn, user_id) {
+        return 0;
+    }
+
+    let code: &String = match code {
+        Some(code) if !code.trim().is_empty() => code,
+        _ => return 2
+    };
+
+    if is_second_factor_locked(conn, user_id) {
+        return 4;
+    }
+
+    if verify_totp(conn, user_id, code) || use_recovery_code(conn, user_id, code) {
+        reset_code_failures(conn, user_id);
+        return 1;
+    }
+
+    record_code_failure(conn, user_id);
+    return 3;
+}
+
+// Authentication methods (RFC 8176) of a password login, given the result of check_second_factor
+pub fn login_amr(second_factor: u8) -> Vec<String> {
+    if second_factor == 1 {
+        return vec![String::from("pwd"), String::from("otp"), String::from("mfa")];
+    }
+    return vec![String::from("pwd")];
+}
+
+// 0 - secret created, 1 - wrong password, 2 - already enabled, 3 - internal error
+// The new secret only has to be entered in the authenticator app, it isn't used for logging in until it is confirmed.
+fn setup_totp(conn: &mut postgres::Client, user_id: &String, password: &String) -> (u8, Option<String>) {
+    if !verify_password(conn, user_id, password) {
+        return (1, None);
+    }
+    if is_two_factor_enabled(conn, user_id) {
+        return (2, None);
+    }
+
+    let secret: [u8; 20] = rand::thread_rng().gen();
+    let secret_: Vec<u8> = secret.to_vec();
+    if conn.execute("UPDATE users SET totp_secret=$1, totp_last_step = NULL WHERE id=$2", &[&secret_, user_id]).is_err() {
+        return (3, None);
+    }
+
+    let label: String = format!("{}:{}", TOTP_ISSUER, get_username_by_id(conn, user_id));
+    let uri: String = format!("otpauth://totp/{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+                              encode(&label), base32(&secret), encode(TOTP_ISSUER), TOTP_DIGITS, TOTP_STEP);
+    return (0, Some(uri));
+}
+
+// 0 - enabled, 1 - no secret was set up, 2 - wrong code, 3 - internal error, 4 - locked after too many wrong codes
+// The current browser's session counts as a two-factor login from now on, every other session is logged out.
+fn enable_totp(conn: &mut postgres::Client, user_id: &String, code: &str, series_id: &String) -> (u8, Option<Vec<String>>) {
+    match conn.query_opt("SELECT totp_enabled FROM users WHERE id=$1 AND totp_secret IS NOT NULL", &[user_id]) {
+        Ok(Some(row)) => {
+            let enabled: bool = row.get(0);
+            if enabled {
+                return (1, None);
+            }
+        },
+        Ok(None) => return (1, None),
+        Err(_e) => return (3, None)
+    }
+
+    if is_second_factor_locked(conn, user_id) {
+        return (4, None);
+    }
+    if !verify_totp(conn, user_id, code) {
+        record_code_failure(conn, user_id);
+        return (2, None);
+    }
+    reset_code_failures(conn, user_id);
+
+    let codes: Vec<String> = match create_recovery_codes(conn, user_id) {
+        Some(codes) => codes,
+        None => return (3, None)
+    };
+
+    let amr: String = login_amr(1).join(" ");
+    let mut transaction = match conn.transaction() {
+        Ok(transaction) => transaction,
+        Err(_e) => return (3, None)
+    };
+    let enabled = transaction.execute("UPDATE users SET totp_enabled = true WHERE id=$1", &[user_id]);
+    let sessions = transaction.execute("DELETE FROM login_tokens WHERE user_id=$1 AND series_id<>$2", &[user_id, series_id]);
+    let session = transaction.execute("UPDATE login_tokens SET amr=$1 WHERE user_id=$2 AND series_id=$3", &[&amr, user_id, series_id]);
+    if enabled.is_err() || sessions.is_err() || session.is_err() || transaction.commit().is_err() {
+        return (3, None);
+    }
+
+    record_security_event(conn, user_id, None, "two_factor_enabled", "two-factor authentication was turned on");
+    return (0, Some(codes));
+}
+
+// 0 - disabled, 1 - wrong password or code, 2 - internal error, 3 - locked after too many wrong codes
+fn disable_totp(conn: &mut postgres::Client, user_id: &String, password: &String, code: &Option<String>) -> u8 {
+    if !verify_password(conn, user_id, password) {
+        return 1;
+    }
+    match check_second_factor(conn, user_id, code) {
+        1 => {},
+        4 => return 3,
+        _ => return 1
+    }
+
+    let mut transaction = match conn.transaction() {
+        Ok(transaction) => transaction,
+        Err(_e) => return 2
+    };
+    let disabled = transaction.execute("UPDATE users SET totp_enabled = false, totp_secret = NULL, totp_last_step = NULL, totp_failures = 0, totp_locked_until = NULL WHERE id=$1", &[user_id]);
+    let codes = transaction.execute("DELETE FROM recovery_codes WHERE user_id=$1", &[user_id]);
+    if disabled.is_err() || codes.is_err() || transaction.commit().is_err() {
+        return 2;
+    }
+
+    record_security_event(conn, user_id, None, "two_factor_disabled", "two-factor authentication was turned off");
+    return 0;
+}
+
+#[post("/setup", format = "json", data = "<input>")]
+async fn setup(conn: UsersDBConnection, cookies: &CookieJar<'_>, input: Json<SetupRequest>) -> (Status, (ContentType, String)) {
+    let user_id: String = match get_session_user_id(&conn, cookies).await {
+        Some(user_id) => user_id,
+        None => return (Status::Unauthorized, (ContentType::JSON, String::from("{\"status_code\": 401, \"error\": \"not logged in\", \"success\": false}")))
+    };
+    let password: String = match input.into_inner().password {
+        Some(password) => password,
+        None => return (Status::BadRequest, (ContentType::JSON, String::from("{\"status_code\": 400, \"error\": \"invalid request\", \"success\": false}")))
+    };
+
+    let (res, uri): (u8, Option<String>) = conn.run(move |c| {
+        return setup_totp(c, &user_id, &password);
+    }).await;
+
+    return match (res, uri) {
+        (0, Some(uri)) => {
+            let qr_code: String = match QrCode::new(uri.as_bytes()) {
+                Ok(qr_code) => qr_code.render::<svg::Color>().min_dimensions(200, 200).build(),
+                Err(_e) => return (Status::InternalServerError, (ContentType::JSON, String::from("{\"status_code\": 500, \"error\": \"internal server error\", \"success\": false}")))
+            };
+            let response_json = json!({
+                "status_code": 200,
+                "error": null,
+                "otpauth_uri": uri,
+                "qr_code": qr_code,
+                "success": true
+            });
+            (Status::Ok, (ContentType::JSON, response_json.to_string()))
+        },
+        (1, _) => (Status::Forbidden, (ContentType::JSON, String::from("{\"status_code\": 403, \"error\": \"wrong password\", \"success\": false}"))),
+        (2, _) => (Status::Conflict, (ContentType::JSON, String::from("{\"status_code\": 409, \"error\": \"two-factor authentication already enabled\", \"success\": false}"))),
+        _ => (Status::InternalServerError, (ContentType::JSON, String::from("{\"status_code\": 500, \"error\": \"internal server error\", \"success\": false}")))
+    };
+}
+
+#[post("/enable", format = "json", data = "<input>")]
+async fn enable(conn: UsersDBConnection, cookies: &CookieJar<'_>, input: Json<CodeRequest>) -> (Status, (ContentType, String)) {
+    let user_id: String = match get_session_user_id(&conn, cookies).await {
+        Some(user_id) => user_id,
+        None => return (Status::Unauthorized, (ContentType::JSON, String::from("{\"status_code\": 401, \"error\": \"not logged in\", \"success\": false}")))
+    };
+    let series_id: String = match cookies.get_private("series_id") {
+        Some(cookie) => String::from(cookie.value()),
+        None => return (Status::Unauthorized, (ContentType::JSON, String::from("{\"status_code\": 401, \"error\": \"not logged in\", \"success\": false}")))
+    };
+    let code: String = match input.into_inner().code {
+        Some(code) => code,
+        None => return (Status::BadRequest, (ContentType::JSON, String::from("{\"status_code\": 400, \"error\": \"invalid request\", \"success\": false}")))
+    };
+
+    let (res, codes): (u8, Option<Vec<String>>) = conn.run(move |c| {
+        return enable_totp(c, &user_id, &code, &series_id);
+    }).await;
+
+    return match res {
+        0 => {
+            let response_json = json!({
+                "status_code": 200,
+                "error": null,
+                "recovery_codes": codes,
+                "success": true
+            });
+            (Status::Ok, (ContentType::JSON, response_json.to_string()))
+        },
+        1 => (Status::Conflict, (ContentType::JSON, String::from("{\"status_code\": 409, \"error\": \"two-factor authentication was not set up\", \"success\": false}"))),
+        2 => (Status::Forbidden, (ContentType::JSON, String::from("{\"status_code\": 403, \"error\": \"wrong code\", \"success\": false}"))),
+        4 => (Status::TooManyRequests, (ContentType::JSON, String::from("{\"status_code\": 429, \"error\": \"too many wrong codes, try again later\", \"success\": false}"))),
+        _ => (Status::InternalServerError, (ContentType::JSON, String::from("{\"status_code\": 500, \"error\": \"internal server error\", \"success\": false}")))
+    };
+}
+
+#[post("/disable", format = "json", data = "<input>")]
+async fn disable(conn: UsersDBConnection, cookies: &CookieJar<'_>, input: Json<DisableRequest>) -> (Status, (ContentType, String)) {
+    let user_id: String = match get_session_user_id(&conn, cookies).await {
+        Some(user_id) => user_id,
+        None => return (Status::Unauthorized, (ContentType::JSON, String::from("{\"status_code\": 401, \"error\": \"not logged in\", \"success\": false}")))
+    };
+    let req = input.into_inner();
+    let password: String = match req.password {
+        Some(password) => password,
+        None => return (Status::BadRequest, (ContentType::JSON, String::from("{\"status_code\": 400, \"error\": \"invalid request\", \"success\": false}")))
+    };
+    let code: Option<String> = req.code;
+
+    let res: u8 = conn.run(move |c| {
+        return disable_totp(c, &user_id, &password, &code);
+    }).await;
+
+    return match res {
+        0 => (Status::Ok, (ContentType::JSON, String::from("{\"status_code\": 200, \"error\": null, \"success\": true}"))),
+        1 => (Status::Forbidden, (ContentType::JSON, String::from("{\"status_code\": 403, \"error\": \"wrong password or code\", \"success\": false}"))),
+        3 => (Status::TooManyRequests, (ContentType::JSON, String::from("{\"status_code\": 429, \"error\": \"too many wrong codes, try again later\", \"success\": false}"))),
+        _ => (Status::InternalServerError, (ContentType::JSON, String::from("{\"status_code\": 500, \"error\": \"internal server error\", \"success\": false}")))
+    };
+}
+
+// Replaces the recovery codes, for when they are used up or got lost
+#[post("/recovery_codes", format = "json", data = "<input>")]
+async fn recovery_codes(conn: UsersDBConnection, cookies: &CookieJar<'_>, input: Json<CodeRequest>) -> (Status, (ContentType, String)) {
+    let user_id: String = match get_session_user_id(&conn, cookies).await {
+        Some(user_id) => user_id,
+        None => return (Status::Unauthorized, (ContentType::JSON, String::from("{\"status_code\": 401, \"error\": \"not logged in\", \"success\": false}")))
+    };
+    let code: Option<String> = input.into_inner().code;
+
+    let (res, codes): (u8, Option<Vec<String>>) = conn.run(move |c| {
+        match check_second_factor(c, &user_id, &code) {
+            1 => {},
+            4 => return (3, None),
+            _ => return (1, None)
+        }
+        return match create_recovery_codes(c, &user_id) {
+            Some(codes) => {
+                record_security_event(c, &user_id, None, "recovery_codes_created", "new recovery codes were created");
+                (0, Some(codes))
+            },
+            None => (2, None)
+        };
+    }).await;
+
+    return match res {
+        0 => {
+            let response_json = json!({
+                "status_code": 200,
+                "error": null,
+                "recovery_codes": codes,
+                "success": true
+            });
+            (Status::Ok, (ContentType::JSON, response_json.to_string()))
+        },
+        1 => (Status::Forbidden, (ContentType::JSON, String::from("{\"status_code\": 403, \"error\": \"wrong code\", \"success\": false}"))),
+        3 => (Status::TooManyRequests, (ContentType::JSON, String::from("{\"status_code\": 429, \"error\": \"too many wrong codes, try again later\", \"success\": false}"))),
+        _ => (Status::InternalServerError, (ContentType::JSON, String::from("{\"status_code\": 500, \"error\": \"internal server error\", \"success\": false}")))
+    };
+}
+
+pub fn stage() -> Vec<rocket::Route> {
+    routes![setup, enable, disable, recovery_codes]
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::{base32, totp, BASE32_ALPHABET, TOTP_STEP};
+
+    fn base32_decode(encoded: &str) -> Vec<u8> {
+        let mut decoded: Vec<u8> = Vec::new();
+        let mut buffer: u32 = 0;
+        let mut bits: u32 = 0;
+
+        for c in encoded.bytes() {
+            let value: u32 = BASE32_ALPHABET.iter().position(|&a| a == c).unwrap() as u32;
+            buffer = (buffer << 5) | value;
+            bits += 5;
+            if bits >= 8 {
+                decoded.push((buffer >> (bits - 8)) as u8);
+                bits -= 8;
+            }
+        }
+
+        return decoded;
+    }
+
+    // RFC 6238 Appendix B, SHA1. The vectors have 8 digits, the codes here are their last 6.
+    #[test]
+    fn totp_matches_rfc_vectors() {
+        let secret: &[u8] = b"12345678901234567890";
+        let vectors: [(u64, u32); 6] = [
+            (59, 94287082),
+            (1111111109, 7081804),
+            (1111111111, 14050471),
+            (1234567890, 89005924),
+            (2000000000, 69279037),
+            (20000000000, 65353130)
+        ];
+
+        for (time, code) in vectors {
+            assert_eq!(totp(secret, time / TOTP_STEP), code % 1_000_000, "T = {}", time);
+        }
+    }
+
+    // RFC 4648 section 10, without padding
+    #[test]
+    fn base32_matches_rfc_vectors() {
+        let vectors: [(&str, &str); 7] = [
+            ("", ""),
+            ("f", "MY"),
+            ("fo", "MZXQ"),
+            ("foo", "MZXW6"),
+            ("foob", "MZXW6YQ"),
+            ("fooba", "MZXW6YTB"),
+            ("foobar", "MZXW6YTBOI")
+        ];
+
+        for (bytes, encoded) in vectors {
+            assert_eq!(base32(bytes.as_bytes()), encoded);
+        }
+    }
+
+    #[test]
+    fn base32_round_trips_secrets() {
+        for _i in 0..100 {
+            let secret: [u8; 20] = rand::thread_rng().gen();
+            let encoded: String = base32(&secret);
+            assert_eq!(encoded.len(), 32);
+            assert_eq!(base32_decode(&encoded), secret.to_vec());
+        }
+    }
+}
\ No newline at end of file
@@ -7,13 +7,27 @@ use rand::Rng;
 use rocket::http::{ContentType, Status};
 use rocket::serde::{Deserialize, Serialize};
 use rocket::serde::json::Json;
+use rocket::serde::json::serde_json::json;
 use rocket_sync_db_pools::postgres;
 use rocket::http::{Cookie, SameSite, CookieJar};
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+use rocket::State;
+use hmac::{Hmac, Mac, NewMac};
 use sha3::{Digest, Sha3_512};
 
 use super::super::db::UsersDBConnection;
-
-const ARGON_CONFIG: Config = Config {
+use super::clients::get_client_info;
+use super::scopes::{parse_scope, scope_includes, scope_to_vec};
+use super::consents::has_consent;
+use super::events::record_security_event;
+use super::oauth::{scope_string, OAuthConfig, TokenError, TokenResponse};
+use super::verification::{meets_email_requirement, send_verification_email};
+use super::deletion::is_deletion_scheduled;
+use super::two_factor::{check_second_factor, login_amr};
+use crate::mail::Mailer;
+
+pub const ARGON_CONFIG: Config = Config {
     variant: Variant::Argon2id,
     version: Version::Version13,
     mem_cost: 65536,
@@ -33,6 +47,16 @@ const ACCESS_TOKEN_LENGTH: usize = 128;
 //Duration in seconds (one month)
 const ACCESS_TOKEN_DURATION: u64 = 60*60*24*30;
 
+//Durations in seconds, a refresh token expires if unused for two months and its family expires a year after login
+const REFRESH_TOKEN_IDLE_DURATION: u64 = 60*60*24*60;
+const REFRESH_TOKEN_ABSOLUTE_DURATION: u64 = 60*60*24*365;
+
+//Duration in seconds (one hour), clients can get a new token for themselves at any time
+pub const CLIENT_TOKEN_DURATION: u64 = 60*60;
+
+//Duration in seconds (one minute)
+const LAST_USED_PRECISION: u64 = 60;
+
 #[derive(Serialize, Deserialize, FromForm)]
 #[serde(crate = "rocket::serde")]
 struct UserIn{
@@ -49,8 +73,8 @@ struct AuthenticationRequest{
     client_id: Option<String>,
     scope: u64,
     response_type: Option<String>,
-    refresh_token: Option<String>,
     remember: bool,
+    otp: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, FromForm, Clone)]
@@ -60,18 +84,82 @@ struct CookieAuthenticationRequest{
     scope: u64,
 }
 
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct SessionRequest{
+    username: Option<String>,
+    password: Option<String>,
+    otp: Option<String>
+}
+
 #[derive(Serialize, Deserialize, FromForm)]
 #[serde(crate = "rocket::serde")]
-struct AuthenticationResponse{
-    access_token: Option<String>,
-    refresh_token: Option<String>,
-    user_id: Option<String>,
-    client_name: Option<String>,
-    internal: bool,
-    expiration: Option<u64>,
-    status_code: Option<u16>,
-    error: Option<String>,
-    success: bool
+pub struct AuthenticationResponse{
+    pub access_token: Option<String>,
+    pub refresh_token: Option<String>,
+    pub id_token: Option<String>,
+    pub token_type: Option<String>,
+    pub user_id: Option<String>,
+    pub client_name: Option<String>,
+    pub internal: bool,
+    pub expiration: Option<u64>,
+    pub expires_in: Option<u64>,
+    pub amr: Option<Vec<String>>,
+    pub status_code: Option<u16>,
+    pub error: Option<String>,
+    pub success: bool
+}
+
+impl AuthenticationResponse {
+    pub fn error(status_code: u16, error: &str) -> AuthenticationResponse {
+        return AuthenticationResponse{
+            access_token: None,
+            refresh_token: None,
+            id_token: None,
+            token_type: None,
+            user_id: None,
+            client_name: None,
+            internal: false,
+            expiration: None,
+            expires_in: None,
+            amr: None,
+            status_code: Some(status_code),
+            error: Some(status_code.to_string() + "; " + error),
+            success: false
+        };
+    }
+}
+
+// User-Agent header of the request, stored with token sets so users can tell their devices apart
+pub struct UserAgent(pub Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for UserAgent {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let user_agent: Option<String> = request.headers().get_one("User-Agent").map(String::from);
+        return Outcome::Success(UserAgent(user_agent));
+    }
+}
+
+// What a token set is issued for, the user and client plus the scope and authentication methods the user signed in with
+pub struct TokenGrant{
+    pub user_id: String,
+    pub client_id: String,
+    pub scope: u64,
+    pub user_agent: Option<String>,
+    pub amr: Vec<String>
+}
+
+pub struct TokenInfo{
+    pub user_id: String,
+    pub client_id: String,
+    pub scope: u64,
+    pub expiration: u64,
+    pub issued: u64,
+    // Issued with the client credentials grant, the client itself is the subject
+    pub client_token: bool
 }
 
 #[derive(Serialize, Deserialize, FromForm)]
@@ -79,10 +167,11 @@ struct AuthenticationResponse{
 struct ValidationRequest {
     user_id: Option<String>,
     access_token: Option<String>,
-    client_id: Option<String>
+    client_id: Option<String>,
+    scope: Option<u64>
 }
 
-fn random_bytes() -> String {
+pub fn random_bytes() -> String {
     (0..ACCESS_TOKEN_LENGTH)
         .map(|_| {
             let idx = rand::thread_rng().gen_range(0..CHARSET.len());
@@ -91,7 +180,7 @@ fn random_bytes() -> String {
         .collect()
 }
 
-fn random_bytes_l(len: u32) -> String {
+pub fn random_bytes_l(len: u32) -> String {
     (0..len)
         .map(|_| {
             let idx = rand::thread_rng().gen_range(0..CHARSET.len());
@@ -109,10 +198,41 @@ pub fn to_hex_string(bytes: &[u8]) -> String {
     return s;
 }
 
+// OAuth tokens are only stored as a keyed hash, so a database dump alone can't be used to impersonate anyone
+pub fn hash_token(token: &String, token_secret: &String) -> String {
+    let mut mac = Hmac::<Sha3_512>::new_varkey(token_secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(token.as_bytes());
+    let token_hash = mac.finalize().into_bytes();
+    return to_hex_string(token_hash.as_ref());
+}
+
+// Replaces plaintext tokens stored before tokens were hashed with their hashes, returns the number of migrated rows
+pub fn migrate_plaintext_tokens(conn: &mut postgres::Client, token_secret: &String) -> u64{
+    let rows = match conn.query("SELECT family_id, access_token, refresh_token FROM tokens WHERE tokens_hashed = false", &[]) {
+        Ok(rows) => rows,
+        Err(_e) => return 0
+    };
+
+    let mut migrated: u64 = 0;
+    for row in rows {
+        let family_id: String = row.get(0);
+        let access_token: String = row.get(1);
+        let refresh_token: String = row.get(2);
+
+        let result = conn.execute("UPDATE tokens SET access_token=$1, refresh_token=$2, tokens_hashed = true WHERE family_id=$3 AND tokens_hashed = false",
+                                  &[&hash_token(&access_token, token_secret), &hash_token(&refresh_token, token_secret), &family_id]);
+        if let Ok(rows_updated) = result {
+            migrated += rows_updated;
+        }
+    }
+
+    return migrated;
+}
+
 // Returns status code (u8)
 // Status codes are: 0 - success, 1 - username taken, 2 - username/password/email empty, 3 - email taken, 4 - internal error
-fn create_user(conn: &mut postgres::Client, user: UserIn) -> u8{
-    if user.username.is_none() || user.password.is_none() { 
+fn create_user(conn: &mut postgres::Client, user: UserIn, user_id: &String) -> u8{
+    if user.username.is_none() || user.password.is_none() || user.email.is_none() { 
         return 2;
     }
 
@@ -132,39 +252,156 @@ fn create_user(conn: &mut postgres::Client, user: UserIn) -> u8{
     }
 
     let salt = random_bytes_l(32);
-    let password = argon2::hash_encoded(user.password.unwrap().as_bytes(), &salt.as_bytes(), &ARGON_CONFIG).unwrap();
-
-    let user_id = random_bytes();
+    let password = argon2::hash_encoded(user.password.unwrap().as_bytes(), salt.as_bytes(), &ARGON_CONFIG).unwrap();
 
-    let rows_updated = conn.execute("INSERT INTO users (id, username, password, salt, email) VALUES ($1, $2, $3, $4, $5)", &[&user_id, &username, &password, &salt, &email]).unwrap();
+    let rows_updated = conn.execute("INSERT INTO users (id, username, password, salt, email) VALUES ($1, $2, $3, $4, $5)", &[user_id, &username, &password, &salt, &email]).unwrap();
 
     return if rows_updated == 1 { 0 } else { 4 };
 }
 
 // 0 - authenticated, 1 - not authenticated, 2 - internal error
 pub fn is_user_session_authenticated(conn: &mut postgres::Client, user_id: String, series_id: String, token: String) -> u8{
+    return authenticate_session(conn, &user_id, &series_id, &token).0;
+}
+
+// Same status codes as is_user_session_authenticated, also returns the authentication methods (RFC 8176) of the login
+// that started the session. Sessions started without a second factor don't count once the user turned on 2FA.
+pub fn authenticate_session(conn: &mut postgres::Client, user_id: &String, series_id: &String, token: &String) -> (u8, Vec<String>){
     let mut sha = Sha3_512::default();
     sha.update(token);
     let token_hash = sha.finalize();
     if token_hash.is_empty() {
-        return 2;
+        return (2, Vec::new());
     }
     let token_hash_: &[u8] = token_hash.as_ref();
     let token_hex: String = to_hex_string(token_hash_);
-    let res = conn.query("SELECT * FROM login_tokens WHERE user_id=$1 AND series_id=$2 AND token=$3",
-                                                            &[&user_id, &series_id, &token_hex]);
+    let res = conn.query_opt("SELECT login_tokens.amr, users.totp_enabled FROM login_tokens INNER JOIN users ON users.id = login_tokens.user_id WHERE login_tokens.user_id=$1 AND login_tokens.series_id=$2 AND login_tokens.token=$3",
+                                                            &[user_id, series_id, &token_hex]);
 
-    if res.is_err(){
-        return 2;
+    let row = match res {
+        Ok(Some(row)) => row,
+        Ok(None) => return (1, Vec::new()),
+        Err(_e) => return (2, Vec::new())
+    };
+    let amr_: String = row.get(0);
+    let totp_enabled: bool = row.get(1);
+    let amr: Vec<String> = amr_.split_whitespace().map(String::from).collect();
+
+    if totp_enabled && !amr.iter().any(|method| method == "otp") {
+        return (1, Vec::new());
     }
-    if res.unwrap().is_empty(){
-        return 1;
+
+    return (0, amr);
+}
+
+// Stores a hashed remember-me token for the user with the authentication methods of the login, returns false on failure
+pub fn remember_user(conn: &mut postgres::Client, user_id: &String, series_id: &String, token: &String, amr: &[String]) -> bool{
+    let mut sha = Sha3_512::default();
+    sha.update(token);
+    let token_hash = sha.finalize();
+    if token_hash.is_empty() {
+        return false;
+    }
+    let token_hash_: &[u8] = token_hash.as_ref();
+    let token_hex: String = to_hex_string(token_hash_);
+
+    let result = conn.execute("INSERT INTO login_tokens (user_id, series_id, token, amr) VALUES ($1, $2, $3, $4)", &[user_id, series_id, &token_hex, &amr.join(" ")]);
+    return result.is_ok();
+}
+
+pub fn add_remember_cookies(cookies: &CookieJar<'_>, user_id: String, series_id: String, token: String){
+    let mut user_id_cookie: Cookie = Cookie::build("user_id", user_id)
+                            .path("/")
+                            .secure(true)
+                            .http_only(true)
+                            .same_site(SameSite::Strict)
+                            .finish();
+    let mut series_id_cookie: Cookie = Cookie::build("series_id", series_id)
+                            .path("/")
+                            .secure(true)
+                            .http_only(true)
+                            .same_site(SameSite::Strict)
+                            .finish();
+    let mut token_cookie: Cookie = Cookie::build("token", token)
+                            .path("/")
+                            .secure(true)
+                            .http_only(true)
+                            .same_site(SameSite::Strict)
+                            .finish();
+    user_id_cookie.make_permanent();
+    series_id_cookie.make_permanent();
+    token_cookie.make_permanent();
+
+    cookies.add_private(user_id_cookie);
+    cookies.add_private(series_id_cookie);
+    cookies.add_private(token_cookie);
+}
+
+// Returns the id of the user remembered by the session cookies, if they are valid
+pub async fn get_session_user_id(conn: &UsersDBConnection, cookies: &CookieJar<'_>) -> Option<String>{
+    return get_session(conn, cookies).await.map(|(user_id, _amr)| user_id);
+}
+
+// Returns the user id and the authentication methods of the session, if the session cookies are valid
+pub async fn get_session(conn: &UsersDBConnection, cookies: &CookieJar<'_>) -> Option<(String, Vec<String>)>{
+    let series_id_cookie = cookies.get_private("series_id");
+    let token_cookie = cookies.get_private("token");
+    let user_id_cookie = cookies.get_private("user_id");
+
+    if series_id_cookie.is_none() || token_cookie.is_none() || user_id_cookie.is_none() {
+        return None;
     }
 
-    return 0;
+    let series_id: String = String::from(series_id_cookie.unwrap().value());
+    let token: String = String::from(token_cookie.unwrap().value());
+    let user_id: String = String::from(user_id_cookie.unwrap().value());
+    let user_id_: String = user_id.clone();
+
+    let (res, amr): (u8, Vec<String>) = conn.run(move |c| {
+        return authenticate_session(c, &user_id, &series_id, &token);
+    }).await;
+
+    return if res == 0 { Some((user_id_, amr)) } else { None };
 }
 
-fn user_by_name_exists(conn: &mut postgres::Client, username: &String) -> u8{
+// Keeps the user logged in on this browser with the remember-me cookies
+pub async fn start_session(conn: &UsersDBConnection, cookies: &CookieJar<'_>, user_id: String, amr: Vec<String>) -> bool{
+    let series_id: String = random_bytes();
+    let token: String = random_bytes();
+    let series_id_: String = series_id.clone();
+    let token_: String = token.clone();
+    let user_id_: String = user_id.clone();
+
+    let remembered: bool = conn.run(move |c| {
+        return remember_user(c, &user_id_, &series_id, &token, &amr);
+    }).await;
+
+    if remembered {
+        add_remember_cookies(cookies, user_id, series_id_, token_);
+    }
+    return remembered;
+}
+
+// Returns the user's id if the username and password match
+pub fn get_user_id_by_credentials(conn: &mut postgres::Client, username: &String, password_in: &String) -> Option<String>{
+    let user_info = conn.query_one("SELECT password, id FROM users WHERE username=$1", &[username]);
+
+    if user_info.is_err() {
+        return None;
+    }
+
+    let user_info_raw = user_info.unwrap();
+
+    let password: String = user_info_raw.get(0);
+    let user_id: String = user_info_raw.get(1);
+
+    if argon2::verify_encoded(&password, password_in.as_bytes()).unwrap_or(false) {
+        return Some(user_id);
+    }
+    return None;
+}
+
+pub fn user_by_name_exists(conn: &mut postgres::Client, username: &String) -> u8{
     let result = conn.query("SELECT username FROM users WHERE username = $1", &[username]);
     return match result {
         Ok(row) => if row.is_empty() { 0 } else { 1 },
@@ -172,7 +409,7 @@ fn user_by_name_exists(conn: &mut postgres::Client, username: &String) -> u8{
     }
 }
 
-fn user_by_email_exists(conn: &mut postgres::Client, email: &String) -> u8{
+pub fn user_by_email_exists(conn: &mut postgres::Client, email: &String) -> u8{
     let result = conn.query("SELECT username FROM users WHERE email = $1", &[email]);
     return match result {
         Ok(row) => if row.is_empty() { 0 } else { 1 },
@@ -193,22 +430,176 @@ pub fn get_username_by_id(conn: &mut postgres::Client, user_id: &String) -> Stri
     }
 }
 
-// USED ONLY WHEN LOGGING IN WITH COOKIES FOR REMEMBERING USERS (cookies are verified before)
-fn get_access_token_user_id(conn: &mut postgres::Client, user_id: String, client_id: String) -> AuthenticationResponse{
-    let client_info = conn.query_one("SELECT client_name, internal FROM clients WHERE client_id=$1", &[&client_id]);
+// Stores a new token set for the user and client, every grant gets its own set so each device keeps an independent session.
+// Every new set starts a new refresh token family.
+fn store_token_set(conn: &mut postgres::Client, grant: &TokenGrant, access_token: &String, refresh_token: &String, token_secret: &String) -> bool{
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+
+    let access_token_expire: i64 = (since_the_epoch.as_secs() + ACCESS_TOKEN_DURATION) as i64;
+    let refresh_token_expire: i64 = (since_the_epoch.as_secs() + REFRESH_TOKEN_IDLE_DURATION) as i64;
+    let family_expire: i64 = (since_the_epoch.as_secs() + REFRESH_TOKEN_ABSOLUTE_DURATION) as i64;
+    let family_id: String = to_hex_string(&rand::thread_rng().gen::<[u8; 16]>());
+    let scope_: i64 = grant.scope as i64;
+    let created: i64 = since_the_epoch.as_secs() as i64;
+
+    // Sets that can't be used anymore are cleaned up whenever the user gets a new one for the client
+    let cleaned_up = conn.execute("DELETE FROM tokens WHERE user_id=$1 AND client_id=$2 AND (family_expire < $3 OR refresh_token_revoked = true)",
+                                  &[&grant.user_id, &grant.client_id, &created]);
+    if cleaned_up.is_err() {
+        return false;
+    }
+
+    let access_token_hash: String = hash_token(access_token, token_secret);
+    let refresh_token_hash: String = hash_token(refresh_token, token_secret);
+
+    let result = conn.execute("INSERT INTO tokens (user_id, client_id, access_token, access_token_expire, refresh_token, refresh_token_expire, family_id, family_expire, scope, user_agent, created, access_token_created, last_used, tokens_hashed, amr) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $11, $11, true, $12)",
+                              &[&grant.user_id, &grant.client_id, &access_token_hash, &access_token_expire, &refresh_token_hash, &refresh_token_expire, &family_id, &family_expire, &scope_, &grant.user_agent, &created, &grant.amr.join(" ")]);
+    if result.is_err() {
+        return false;
+    }
+
+    // Token sets are deleted once they can't be used anymore, the event keeps the login in the user's history
+    let details: String = format!("logged in with {} for {} on {}", grant.amr.join(" "), scope_to_vec(conn, grant.scope).unwrap_or_default().join(" "), grant.user_agent.as_deref().unwrap_or("an unknown device"));
+    record_security_event(conn, &grant.user_id, Some(&grant.client_id), "login", &details);
+    return true;
+}
+
+// Stores an access token issued to a client for itself, the client is the subject and there is no refresh token.
+// The refresh token column still has to be unique, so it gets a random value that is revoked from the start.
+pub fn store_client_token(conn: &mut postgres::Client, client_id: &String, access_token: &String, scope: u64, token_secret: &String) -> bool{
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+
+    let access_token_expire: i64 = (since_the_epoch.as_secs() + CLIENT_TOKEN_DURATION) as i64;
+    let family_id: String = to_hex_string(&rand::thread_rng().gen::<[u8; 16]>());
+    let scope_: i64 = scope as i64;
+    let created: i64 = since_the_epoch.as_secs() as i64;
+
+    let cleaned_up = conn.execute("DELETE FROM tokens WHERE client_id=$1 AND client_token = true AND (access_token_expire < $2 OR access_token_revoked = true)",
+                                  &[client_id, &created]);
+    if cleaned_up.is_err() {
+        return false;
+    }
+
+    let access_token_hash: String = hash_token(access_token, token_secret);
+    let refresh_token_hash: String = hash_token(&random_bytes(), token_secret);
+
+    let result = conn.execute("INSERT INTO tokens (user_id, client_id, access_token, access_token_expire, refresh_token, refresh_token_expire, family_id, family_expire, scope, refresh_token_revoked, created, access_token_created, last_used, tokens_hashed, client_token) VALUES ($1, $1, $2, $3, $4, $3, $5, $3, $6, true, $7, $7, $7, true, true)",
+                              &[client_id, &access_token_hash, &access_token_expire, &refresh_token_hash, &family_id, &scope_, &created]);
+    return result.is_ok();
+}
+
+// Exchanges a refresh token for a new access and refresh token. The old refresh token is remembered, and if it is ever
+// presented again the whole family is revoked, since either the client or an attacker is holding a stolen copy.
+pub fn rotate_refresh_token(conn: &mut postgres::Client, client_id: &String, refresh_token: &String, token_secret: &String) -> Result<TokenResponse, TokenError>{
+    if !get_client_info(conn, client_id).success {
+        return Err(TokenError::new(401, "invalid_client", "Client authentication failed"));
+    }
+
+    let refresh_token_hash: String = hash_token(refresh_token, token_secret);
+    let token_info = conn.query_opt("SELECT user_id, family_id, family_expire, refresh_token_expire, scope FROM tokens WHERE refresh_token=$1 AND client_id=$2 AND refresh_token_revoked = false",
+                                    &[&refresh_token_hash, client_id]);
+
+    let token_info_raw = match token_info {
+        Ok(Some(row)) => row,
+        Err(_e) => return Err(TokenError::server_error()),
+        Ok(None) => {
+            let reused = conn.query_opt("SELECT family_id FROM rotated_refresh_tokens WHERE refresh_token=$1", &[&refresh_token_hash]);
+            if let Ok(Some(row)) = reused {
+                let family_id: String = row.get(0);
+                let revoked = conn.query("UPDATE tokens SET access_token_revoked = true, refresh_token_revoked = true WHERE family_id=$1 RETURNING user_id, client_id", &[&family_id]);
+                if let Ok(rows) = revoked {
+                    for row in rows {
+                        let family_user_id: String = row.get(0);
+                        let family_client_id: String = row.get(1);
+                        record_security_event(conn, &family_user_id, Some(&family_client_id), "refresh_token_reuse", "a rotated refresh token was used again, the token family was revoked");
+                    }
+                }
+            }
+            return Err(TokenError::new(400, "invalid_grant", "Invalid refresh token"));
+        }
+    };
+
+    let token_user_id: String = token_info_raw.get(0);
+    let family_id: String = token_info_raw.get(1);
+    let family_expire: i64 = token_info_raw.get(2);
+    let refresh_token_expire: i64 = token_info_raw.get(3);
+    let scope: i64 = token_info_raw.get(4);
+
+    if !meets_email_requirement(conn, &token_user_id, client_id) {
+        return Err(TokenError::new(400, "invalid_grant", "The user's email address isn't verified"));
+    }
+
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    let now: i64 = since_the_epoch.as_secs() as i64;
+
+    if family_expire < now || refresh_token_expire < now {
+        return Err(TokenError::new(400, "invalid_grant", "The refresh token expired"));
+    }
+
+    let access_token: String = random_bytes();
+    let new_refresh_token: String = random_bytes();
+    let access_token_expire: i64 = now + ACCESS_TOKEN_DURATION as i64;
+    let new_refresh_token_expire: i64 = now + REFRESH_TOKEN_IDLE_DURATION as i64;
+
+    let mut transaction = match conn.transaction() {
+        Ok(transaction) => transaction,
+        Err(_e) => return Err(TokenError::server_error())
+    };
+
+    // The primary key makes a concurrent second use of the same refresh token fail here
+    let rotated = transaction.execute("INSERT INTO rotated_refresh_tokens (refresh_token, family_id, rotated) VALUES ($1, $2, $3)", &[&refresh_token_hash, &family_id, &now]);
+    if rotated.is_err() {
+        return Err(TokenError::new(400, "invalid_grant", "Invalid refresh token"));
+    }
+
+    let rows_updated = transaction.execute("UPDATE tokens SET access_token=$1, access_token_expire=$2, refresh_token=$3, refresh_token_expire=$4, access_token_revoked=false, access_token_created=$6, last_used=$6 WHERE refresh_token=$5 AND refresh_token_revoked = false",
+                                           &[&hash_token(&access_token, token_secret), &access_token_expire, &hash_token(&new_refresh_token, token_secret), &new_refresh_token_expire, &refresh_token_hash, &now]);
+    if rows_updated.is_err() || rows_updated.unwrap() != 1 || transaction.commit().is_err() {
+        return Err(TokenError::server_error());
+    }
+
+    return Ok(TokenResponse{
+        access_token,
+        token_type: String::from("Bearer"),
+        expires_in: ACCESS_TOKEN_DURATION,
+        refresh_token: Some(new_refresh_token),
+        scope: scope_string(conn, scope as u64),
+        id_token: None
+    });
+}
+
+// Forgets rotated refresh tokens once their family expired or was deleted, a reused token can't do any harm then.
+// Run regularly in the background, returns the number of deleted rows.
+pub fn delete_expired_rotated_refresh_tokens(conn: &mut postgres::Client) -> u64 {
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    let now: i64 = since_the_epoch.as_secs() as i64;
+
+    return conn.execute("DELETE FROM rotated_refresh_tokens WHERE NOT EXISTS (SELECT 1 FROM tokens WHERE tokens.family_id = rotated_refresh_tokens.family_id AND tokens.family_expire >= $1)",
+                        &[&now]).unwrap_or(0);
+}
+
+// USED ONLY WHEN THE USER HAS ALREADY BEEN VERIFIED (remember-me cookies or an authorization code)
+pub fn get_access_token_user_id(conn: &mut postgres::Client, grant: &TokenGrant, token_secret: &String) -> AuthenticationResponse{
+    if scope_to_vec(conn, grant.scope).is_none() {
+        return AuthenticationResponse::error(400, "invalid scope");
+    }
+
+    let client_info = conn.query_one("SELECT client_name, internal FROM clients WHERE client_id=$1", &[&grant.client_id]);
 
     if client_info.is_err() {
-        return AuthenticationResponse{
-            access_token: None,
-            refresh_token: None,
-            expiration: None,
-            user_id: None,
-            client_name: None,
-            internal: false,
-            success: false,
-            status_code: Some(401),
-            error: Some(String::from("401; invalid credentials")),
-        };
+        return AuthenticationResponse::error(401, "invalid credentials");
     }
 
     let client_info_raw = client_info.unwrap();
@@ -216,6 +607,14 @@ fn get_access_token_user_id(conn: &mut postgres::Client, user_id: String, client
     let client_name: String = client_info_raw.get(0);
     let internal: bool = client_info_raw.get(1);
 
+    if !meets_email_requirement(conn, &grant.user_id, &grant.client_id) {
+        return AuthenticationResponse::error(403, "email not verified");
+    }
+
+    if is_deletion_scheduled(conn, &grant.user_id) {
+        return AuthenticationResponse::error(403, "account scheduled for deletion");
+    }
+
     let access_token: String = random_bytes();
 
     let start = SystemTime::now();
@@ -225,40 +624,19 @@ fn get_access_token_user_id(conn: &mut postgres::Client, user_id: String, client
 
     let refresh_token: String = random_bytes();
 
-    let seconds_since: String = (since_the_epoch.as_secs() + ACCESS_TOKEN_DURATION).to_string();
-
-    let query: &String = &(String::from("DO
-            $do$
-            BEGIN
-            IF EXISTS (SELECT FROM tokens WHERE user_id='") + &user_id + &String::from("' AND client_id='") + &client_id + &String::from("') THEN
-            UPDATE tokens SET access_token='") + &access_token + &String::from("', access_token_expire='") + &seconds_since + &String::from("' WHERE user_id='") +
-        &user_id + &String::from("' AND client_id='") + &client_id + &String::from("';
-            ELSE
-            INSERT INTO tokens (user_id, client_id, access_token, access_token_expire, refresh_token) VALUES ('") + &user_id + &String::from("', '") + &client_id + &String::from("', '") + &access_token + &String::from("', '") + &seconds_since + &String::from("', '") + &refresh_token + &String::from("');
-            END IF;
-            END
-            $do$"));
-    let rows_updated = conn.execute(query.as_str(), &[]);
-
-    if rows_updated.is_err() {
-        return AuthenticationResponse {
-            access_token: None,
-            refresh_token: None,
-            user_id: None,
-            expiration: None,
-            client_name: None,
-            internal: false,
-            success: false,
-            status_code: Some(500),
-            error: Some(String::from("500; internal server error")),
-        };
+    if !store_token_set(conn, grant, &access_token, &refresh_token, token_secret) {
+        return AuthenticationResponse::error(500, "internal server error");
     }
 
     return AuthenticationResponse {
         access_token: Some(access_token),
         refresh_token: Some(refresh_token),
+        id_token: None,
+        token_type: Some(String::from("Bearer")),
         expiration: Some(since_the_epoch.as_secs() + ACCESS_TOKEN_DURATION),
-        user_id: Some(user_id),
+        expires_in: Some(ACCESS_TOKEN_DURATION),
+        amr: Some(grant.amr.clone()),
+        user_id: Some(grant.user_id.clone()),
         client_name: Some(client_name),
         internal,
         error: None,
@@ -267,7 +645,7 @@ fn get_access_token_user_id(conn: &mut postgres::Client, user_id: String, client
     };
 }
 
-fn get_access_token(conn: &mut postgres::Client, request: &AuthenticationRequest, series_id: &String, token: &String) -> AuthenticationResponse{
+fn get_access_token(conn: &mut postgres::Client, request: &AuthenticationRequest, series_id: &String, token: &String, user_agent: &Option<String>, token_secret: &String) -> AuthenticationResponse{
     let username: &String = request.username.as_ref().unwrap();
     let response_type: &String = request.response_type.as_ref().unwrap();
     let client_id: &String = request.client_id.as_ref().unwrap();
@@ -277,17 +655,7 @@ fn get_access_token(conn: &mut postgres::Client, request: &AuthenticationRequest
     let client_info = conn.query_one("SELECT client_name, internal FROM clients WHERE client_id=$1", &[client_id]);
 
     if client_info.is_err() {
-        return AuthenticationResponse{
-            access_token: None,
-            refresh_token: None,
-            expiration: None,
-            user_id: None,
-            client_name: None,
-            internal: false,
-            success: false,
-            status_code: Some(401),
-            error: Some(String::from("401; invalid credentials")),
-        };
+        return AuthenticationResponse::error(401, "invalid credentials");
     }
 
     let client_info_raw = client_info.unwrap();
@@ -295,31 +663,43 @@ fn get_access_token(conn: &mut postgres::Client, request: &AuthenticationRequest
     let client_name: String = client_info_raw.get(0);
     let internal: bool = client_info_raw.get(1);
 
+    // The number is a bitmask, 0 is stored as the scopes it stands for right now
+    let scope: u64 = match parse_scope(conn, &request.scope.to_string()) {
+        Some(scope) => scope,
+        None => return AuthenticationResponse::error(400, "invalid scope")
+    };
+
     if response_type == "code"{
         let password_in: &String = request.password.as_ref().unwrap();
 
-        let user_info = conn.query_one("SELECT password, id FROM users WHERE username=$1", &[username]);
+        let user_id_res = get_user_id_by_credentials(conn, username, password_in);
 
-        if user_info.is_err() {
-            return AuthenticationResponse{
-                access_token: None,
-                refresh_token: None,
-                expiration: None,
-                user_id: None,
-                client_name: None,
-                internal: false,
-                success: false,
-                status_code: Some(401),
-                error: Some(String::from("401; invalid credentials")),
-            };
-        }
+        if let Some(user_id) = user_id_res { 
+            if !meets_email_requirement(conn, &user_id, client_id) {
+                return AuthenticationResponse::error(403, "email not verified");
+            }
+
+            if is_deletion_scheduled(conn, &user_id) {
+                return AuthenticationResponse::error(403, "account scheduled for deletion");
+            }
 
-        let user_info_raw = user_info.unwrap();
+            let second_factor: u8 = check_second_factor(conn, &user_id, &request.otp);
+            if second_factor == 2 {
+                return AuthenticationResponse::error(401, "two factor code required");
+            }
+            if second_factor == 3 {
+                return AuthenticationResponse::error(401, "invalid two factor code");
+            }
+            if second_factor == 4 {
+                return AuthenticationResponse::error(429, "too many wrong two factor codes, try again later");
+            }
+            let amr: Vec<String> = login_amr(second_factor);
 
-        let password: String = user_info_raw.get(0);
-        let user_id: String = user_info_raw.get(1);
+            // There is no page here to ask the user, third-party clients have to get consent through /authorize first
+            if !has_consent(conn, &user_id, client_id, scope) {
+                return AuthenticationResponse::error(403, "consent required");
+            }
 
-        if argon2::verify_encoded(&password, &password_in.as_bytes()).unwrap() { 
             let start = SystemTime::now();
             let since_the_epoch = start
                 .duration_since(UNIX_EPOCH)
@@ -327,77 +707,30 @@ fn get_access_token(conn: &mut postgres::Client, request: &AuthenticationRequest
         
             let refresh_token: String = random_bytes();
 
-            let seconds_since: String = (since_the_epoch.as_secs() + ACCESS_TOKEN_DURATION).to_string();
-            
-            let query: &String = &(String::from("DO
-            $do$
-            BEGIN
-            IF EXISTS (SELECT FROM tokens WHERE user_id='") + &user_id + &String::from("' AND client_id='") + client_id + &String::from("') THEN
-            UPDATE tokens SET access_token='") + &access_token + &String::from("', access_token_expire='") + &seconds_since + &String::from("' WHERE user_id='") +
-            &user_id + &String::from("' AND client_id='") + client_id + &String::from("';
-            ELSE
-            INSERT INTO tokens (user_id, client_id, access_token, access_token_expire, refresh_token) VALUES ('") + &user_id + &String::from("', '") + client_id + &String::from("', '") + &access_token + &String::from("', '") + &seconds_since + &String::from("', '") + &refresh_token + &String::from("');
-            END IF;
-            END
-            $do$"));
-            
-            let rows_updated = conn.execute(query.as_str(), &[]);
-
-            if rows_updated.is_err() {
-                return AuthenticationResponse{
-                    access_token: None,
-                    refresh_token: None,
-                    user_id: None,
-                    expiration: None,
-                    client_name: None,
-                    internal: false,
-                    success: false,
-                    status_code: Some(500),
-                    error: Some(String::from("500; internal server error")),
-                };
+            let grant = TokenGrant{
+                user_id,
+                client_id: client_id.clone(),
+                scope,
+                user_agent: user_agent.clone(),
+                amr
+            };
+            if !store_token_set(conn, &grant, &access_token, &refresh_token, token_secret) {
+                return AuthenticationResponse::error(500, "internal server error");
             }
 
-            if remember {
-                let mut sha = Sha3_512::default();
-                sha.update(token);
-                let token_hash = sha.finalize();
-                if token_hash.is_empty() {
-                    return AuthenticationResponse{
-                        access_token: None,
-                        refresh_token: None,
-                        user_id: None,
-                        expiration: None,
-                        client_name: None,
-                        internal: false,
-                        success: false,
-                        status_code: Some(500),
-                        error: Some(String::from("500; internal server error")),
-                    };
-                }
-                let token_hash_: &[u8] = token_hash.as_ref();
-                let token_hex: String = to_hex_string(token_hash_);
-
-                let result = conn.execute("INSERT INTO login_tokens VALUES ($1, $2, $3)", &[&user_id, series_id, &token_hex]);
-                if result.is_err() {
-                    return AuthenticationResponse{
-                        access_token: None,
-                        refresh_token: None,
-                        user_id: None,
-                        expiration: None,
-                        client_name: None,
-                        internal: false,
-                        success: false,
-                        status_code: Some(500),
-                        error: Some(String::from("500; internal server error")),
-                    };
-                }
+            if remember && !remember_user(conn, &grant.user_id, series_id, token, &grant.amr) {
+                return AuthenticationResponse::error(500, "internal server error");
             }
 
             return AuthenticationResponse{
                 access_token: Some(access_token),
                 refresh_token: Some(refresh_token),
+                id_token: None,
+                token_type: Some(String::from("Bearer")),
                 expiration: Some(since_the_epoch.as_secs() + ACCESS_TOKEN_DURATION),
-                user_id: Some(user_id),
+                expires_in: Some(ACCESS_TOKEN_DURATION),
+                amr: Some(grant.amr),
+                user_id: Some(grant.user_id),
                 client_name: Some(client_name),
                 internal,
                 error: None,
@@ -405,120 +738,104 @@ fn get_access_token(conn: &mut postgres::Client, request: &AuthenticationRequest
                 success: true,
             };
         }else{ 
-            return AuthenticationResponse{
-                access_token: None,
-                refresh_token: None,
-                expiration: None,
-                user_id: None,
-                client_name: None,
-                internal: false,
-                success: false,
-                status_code: Some(401),
-                error: Some(String::from("401; invalid credentials")),
-            };
+            return AuthenticationResponse::error(401, "invalid credentials");
         }
     }else if response_type == "refresh" {
-        println!("Access Token refresh");
-        let user_info = conn.query_one("SELECT id FROM users WHERE username=$1;", &[username]);
-
-        if user_info.is_err() {
-            return AuthenticationResponse{
-                access_token: None,
-                refresh_token: None,
-                expiration: None,
-                user_id: None,
-                client_name: None,
-                internal: false,
-                success: false,
-                status_code: Some(401),
-                error: Some(String::from("401; invalid credentials")),
-            };
-        }
-
-        let user_info_raw = user_info.unwrap();
+        // Refreshing needs the client to authenticate, which only the token endpoint does
+        return AuthenticationResponse::error(400, "refresh tokens have to be exchanged at /token");
+    }else{
+        return AuthenticationResponse::error(400, "invalid response type");
+    }
+}
 
-        let user_id: String = user_info_raw.get(0);
+// Looks up an access token, returns None if the token doesn't exist or was revoked (expiry is left to the caller)
+pub fn get_token_info(conn: &mut postgres::Client, access_token: &String, token_secret: &String) -> Option<TokenInfo>{
+    let access_token_hash: String = hash_token(access_token, token_secret);
+    let response_raw = conn.query_one("SELECT user_id, client_id, scope, access_token_expire, access_token_created, client_token FROM tokens WHERE access_token=$1 AND access_token_revoked = false", &[&access_token_hash]);
 
+    if response_raw.is_err() {
+        return None;
+    }
 
-        let start = SystemTime::now();
-        let since_the_epoch = start
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards");
+    let response = response_raw.unwrap();
+    let user_id: String = response.get(0);
+    let client_id: String = response.get(1);
+    let scope: i64 = response.get(2);
+    let expire_time: i64 = response.get(3);
+    // access_token_created is when this access token was issued, created would be when its token family started
+    let issued: i64 = response.get(4);
+    let client_token: bool = response.get(5);
 
-        let mut timestamp: i64 = 0;
-        timestamp = timestamp.wrapping_add((since_the_epoch.as_secs() + ACCESS_TOKEN_DURATION) as i64);
+    let mut expire_as_u: u64 = 0;
+    expire_as_u = expire_as_u.wrapping_add(expire_time as u64);
 
-        let refresh_token: String = request.refresh_token.as_ref().unwrap().clone();
-        let access_token_req = conn.execute("UPDATE tokens SET access_token = $1, access_token_expire = $5 WHERE client_id=$2 AND user_id=$3 AND refresh_token = $4;",
-        &[&access_token, client_id, &user_id, &refresh_token, &timestamp]);
+    // Shown on the connected apps page, it is only written once a minute so checking a token stays a read most of the time
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    let now: i64 = since_the_epoch.as_secs() as i64;
+    let _ = conn.execute("UPDATE tokens SET last_used=$1 WHERE access_token=$2 AND (last_used IS NULL OR last_used < $3)",
+                         &[&now, &access_token_hash, &(now - LAST_USED_PRECISION as i64)]);
+
+    return Some(TokenInfo{
+        // user_id and client_id are char(128) columns and come back padded with spaces
+        user_id: String::from(user_id.trim_end()),
+        client_id: String::from(client_id.trim_end()),
+        scope: scope as u64,
+        expiration: expire_as_u,
+        issued: issued as u64,
+        client_token
+    });
+}
 
-        if access_token_req.is_err() {
-            return AuthenticationResponse{
-                access_token: None,
-                refresh_token: None,
-                expiration: None,
-                user_id: None,
-                client_name: None,
-                internal: false,
-                success: false,
-                status_code: Some(500),
-                error: Some(String::from("500; internal server error")),
-            };
-        }
+// If a required scope is given the token must have been granted all of it
+fn is_user_authenticated(conn: &mut postgres::Client, access_token: &String, client_id: &str, user_id: &str, required_scope: Option<u64>, token_secret: &String) -> (u16, String){
+    let token_info = get_token_info(conn, access_token, token_secret);
 
-        return AuthenticationResponse{
-            access_token: Some(access_token),
-            refresh_token: Some(refresh_token),
-            expiration: Some(since_the_epoch.as_secs() + ACCESS_TOKEN_DURATION),
-            user_id: Some(user_id),
-            client_name: Some(client_name),
-            internal,
-            error: None,
-            status_code: Some(201),
-            success: true,
-        };
-    }else{
-        return AuthenticationResponse{
-            access_token: None,
-            refresh_token: None,
-            expiration: None,
-            user_id: None,
-            client_name: None,
-            internal: false,
-            success: false,
-            status_code: Some(400),
-            error: Some(String::from("400; invalid response type")),
-        };
+    if token_info.is_none() {
+        return (401, String::from("{\"success\": false, \"error_code\": 401, \"error\": \"Invalid credentials\"}"));
     }
-}
 
-fn is_user_authenticated(conn: &mut postgres::Client, access_token: &String, client_id: &String, user_id: &String) -> (u16, String){
-    let response_raw = conn.query_one("SELECT access_token_expire FROM tokens WHERE access_token=$1 AND client_id=$2 AND user_id=$3", &[access_token, client_id, user_id]);
-
-    if response_raw.is_err() {
+    let token_info = token_info.unwrap();
+    if token_info.client_id != client_id.trim_end() || token_info.user_id != user_id.trim_end() || token_info.client_token {
         return (401, String::from("{\"success\": false, \"error_code\": 401, \"error\": \"Invalid credentials\"}"));
     }
 
-    let response = response_raw.unwrap();
     let start = SystemTime::now();
     let since_the_epoch = start
         .duration_since(UNIX_EPOCH)
         .expect("Time went backwards");
-    let expire_time: i64 = response.get(0);
-
-    let mut expire_as_u: u64 = 0;
-    expire_as_u = expire_as_u.wrapping_add(expire_time as u64);
+    let scope: u64 = token_info.scope;
 
-    if expire_as_u < since_the_epoch.as_secs(){
+    if token_info.expiration < since_the_epoch.as_secs(){
         return (401, String::from("{\"success\": false, \"status_code\": 401, \"error\": \"Token expired\"}"));
-    }else{
-        return (200, String::from("{\"success\": true}"))
     }
+
+    if let Some(required_scope) = required_scope {
+        if !scope_includes(scope, required_scope) {
+            return (403, String::from("{\"success\": false, \"status_code\": 403, \"error\": \"Insufficient scope\"}"));
+        }
+    }
+
+    let response_json = json!({
+        "success": true,
+        "scope": scope,
+        "scopes": scope_to_vec(conn, scope).unwrap_or_default()
+    });
+    return (200, response_json.to_string());
 }
 
 #[post("/new", format = "json", data = "<input>", rank = 1)]
-async fn new(conn: UsersDBConnection, input: Json<UserIn>) -> (Status, (ContentType, String)) {
-    let status = conn.run(|c| create_user(c, input.into_inner())).await;
+async fn new(conn: UsersDBConnection, mailer: &State<Mailer>, config: &State<OAuthConfig>, input: Json<UserIn>) -> (Status, (ContentType, String)) {
+    let user_id: String = random_bytes();
+    let user_id_: String = user_id.clone();
+    let status = conn.run(move |c| create_user(c, input.into_inner(), &user_id_)).await;
+
+    // The account works without a verified address, so a mail that couldn't be sent can simply be requested again
+    if status == 0 && !send_verification_email(&conn, mailer, config, user_id.clone()).await {
+        println!("Could not send the verification email to user {}", user_id);
+    }
 
     return match status {
         0 => (Status::Ok, (ContentType::JSON, String::from("{\"status_code\": 201, \"error\": null, \"success\": true}"))),
@@ -530,8 +847,9 @@ async fn new(conn: UsersDBConnection, input: Json<UserIn>) -> (Status, (ContentT
 }
 
 #[post("/authenticate", format = "json", data = "<input>", rank = 1)]
-async fn authenticate(conn: UsersDBConnection, cookies: &CookieJar<'_>, input: Json<AuthenticationRequest>) -> (Status, (ContentType, String)) {
+async fn authenticate(conn: UsersDBConnection, cookies: &CookieJar<'_>, user_agent: UserAgent, config: &State<OAuthConfig>, input: Json<AuthenticationRequest>) -> (Status, (ContentType, String)) {
     let req = input.into_inner();
+    let token_secret: String = config.token_secret.clone();
     let remember: bool = req.remember;
     
     if req.response_type.is_none(){
@@ -547,46 +865,22 @@ async fn authenticate(conn: UsersDBConnection, cookies: &CookieJar<'_>, input: J
     let token_: String = token.clone();
     
     let res: AuthenticationResponse = conn.run(move |c| {
-        return get_access_token(c, &req, &series_id, &token);
+        return get_access_token(c, &req, &series_id, &token, &user_agent.0, &token_secret);
     }).await;
     let res_json: String = rocket::serde::json::serde_json::to_string_pretty(&res).unwrap();
 
     if res.success && remember {
-        
-        let mut user_id_cookie: Cookie = Cookie::build("user_id", res.user_id.unwrap())
-                                .path("/")
-                                .secure(true)
-                                .http_only(true)
-                                .same_site(SameSite::Strict)
-                                .finish();
-        let mut series_id_cookie: Cookie = Cookie::build("series_id", series_id_)
-                                .path("/")
-                                .secure(true)
-                                .http_only(true)
-                                .same_site(SameSite::Strict)
-                                .finish();
-        let mut token_cookie: Cookie = Cookie::build("token", token_)
-                                .path("/")
-                                .secure(true)
-                                .http_only(true)
-                                .same_site(SameSite::Strict)
-                                .finish();
-        user_id_cookie.make_permanent();
-        series_id_cookie.make_permanent();
-        token_cookie.make_permanent();
-
-        cookies.add_private(user_id_cookie);
-        cookies.add_private(series_id_cookie);
-        cookies.add_private(token_cookie);
+        add_remember_cookies(cookies, res.user_id.unwrap(), series_id_, token_);
     }
 
     return (Status::from_code(res.status_code.unwrap()).unwrap(), (ContentType::JSON, res_json));
 }
 
 #[post("/valid", format = "json", data = "<input>", rank = 1)]
-async fn token_valid(conn: UsersDBConnection, input: Json<ValidationRequest>) -> (Status, (ContentType, String)){
+async fn token_valid(conn: UsersDBConnection, config: &State<OAuthConfig>, input: Json<ValidationRequest>) -> (Status, (ContentType, String)){
+    let token_secret: String = config.token_secret.clone();
     let res = conn.run(move |c| {
-        return is_user_authenticated(c, input.access_token.as_ref().unwrap(), input.client_id.as_ref().unwrap(), input.user_id.as_ref().unwrap());
+        return is_user_authenticated(c, input.access_token.as_ref().unwrap(), input.client_id.as_ref().unwrap(), input.user_id.as_ref().unwrap(), input.scope, &token_secret);
     }).await;
 
     return (Status::from_code(res.0).unwrap(), (ContentType::JSON, res.1));
@@ -607,27 +901,84 @@ async fn username_taken_endpoint(conn: UsersDBConnection, username: String) -> (
     }
 }
 
+// Logs the user in to the account pages, which aren't tied to a client
+#[post("/session", format = "json", data = "<input>")]
+async fn session(conn: UsersDBConnection, cookies: &CookieJar<'_>, input: Json<SessionRequest>) -> (Status, (ContentType, String)){
+    let req = input.into_inner();
+
+    if req.username.is_none() || req.password.is_none() {
+        return (Status::BadRequest, (ContentType::JSON, String::from("{\"status_code\": 400, \"error\": \"invalid request\", \"success\": false}")));
+    }
+    let username: String = req.username.unwrap();
+    let password: String = req.password.unwrap();
+    let otp: Option<String> = req.otp;
+
+    let (user_id, second_factor): (String, u8) = match conn.run(move |c| {
+        return get_user_id_by_credentials(c, &username, &password).map(|user_id| {
+            let second_factor: u8 = check_second_factor(c, &user_id, &otp);
+            (user_id, second_factor)
+        });
+    }).await {
+        Some(res) => res,
+        None => return (Status::Unauthorized, (ContentType::JSON, String::from("{\"status_code\": 401, \"error\": \"invalid credentials\", \"success\": false}")))
+    };
+
+    if second_factor == 2 {
+        return (Status::Unauthorized, (ContentType::JSON, String::from("{\"status_code\": 401, \"error\": \"two factor code required\", \"two_factor_required\": true, \"success\": false}")));
+    }
+    if second_factor == 3 {
+        return (Status::Unauthorized, (ContentType::JSON, String::from("{\"status_code\": 401, \"error\": \"invalid two factor code\", \"two_factor_required\": true, \"success\": false}")));
+    }
+    if second_factor == 4 {
+        return (Status::TooManyRequests, (ContentType::JSON, String::from("{\"status_code\": 429, \"error\": \"too many wrong two factor codes, try again later\", \"two_factor_required\": true, \"success\": false}")));
+    }
+
+    if !start_session(&conn, cookies, user_id, login_amr(second_factor)).await {
+        return (Status::InternalServerError, (ContentType::JSON, String::from("{\"status_code\": 500, \"error\": \"internal server error\", \"success\": false}")));
+    }
+
+    return (Status::Ok, (ContentType::JSON, String::from("{\"status_code\": 200, \"error\": null, \"success\": true}")));
+}
+
 #[post("/authenticate_cookie", format = "json", data = "<input>", rank = 1)]
-async fn auth_with_cookie(conn: UsersDBConnection, cookies: &CookieJar<'_>, input: Json<CookieAuthenticationRequest>)  -> (Status, (ContentType, String)){
+async fn auth_with_cookie(conn: UsersDBConnection, cookies: &CookieJar<'_>, user_agent: UserAgent, config: &State<OAuthConfig>, input: Json<CookieAuthenticationRequest>)  -> (Status, (ContentType, String)){
+    let token_secret: String = config.token_secret.clone();
     let series_id_cookie = cookies.get_private("series_id");
     let token_cookie = cookies.get_private("token");
     let user_id_cookie = cookies.get_private("user_id");
-    let client_id: String = input.into_inner().client_id.unwrap();
+    let req = input.into_inner();
+    let client_id: String = req.client_id.unwrap();
+    let scope: u64 = req.scope;
     
-    if series_id_cookie.is_some() && token_cookie.is_some() && user_id_cookie.is_some() {
-        let series_id: String = String::from(series_id_cookie.unwrap().value());
-        let token: String = String::from(token_cookie.unwrap().value());
+    if let (Some(series_id_cookie), Some(token_cookie), Some(user_id_cookie)) = (series_id_cookie, token_cookie, user_id_cookie) {
+        let series_id: String = String::from(series_id_cookie.value());
+        let token: String = String::from(token_cookie.value());
         // idk how to do this better
-        let user_id: String = String::from(user_id_cookie.unwrap().value());
+        let user_id: String = String::from(user_id_cookie.value());
         let user_id_: String = user_id.clone();
 
-        let res = conn.run(move |c| {
-            return is_user_session_authenticated(c, user_id, series_id, token);
+        let (res, amr): (u8, Vec<String>) = conn.run(move |c| {
+            return authenticate_session(c, &user_id, &series_id, &token);
         }).await;
 
         if res == 0{
             let res_access: AuthenticationResponse = conn.run(move |c| {
-                return get_access_token_user_id(c, user_id_, client_id);
+                let scope: u64 = match parse_scope(c, &scope.to_string()) {
+                    Some(scope) => scope,
+                    None => return AuthenticationResponse::error(400, "invalid scope")
+                };
+                // Like the login, this can only hand out tokens the user already approved at /authorize
+                if !has_consent(c, &user_id_, &client_id, scope) {
+                    return AuthenticationResponse::error(403, "consent required");
+                }
+                let grant = TokenGrant{
+                    user_id: user_id_,
+                    client_id,
+                    scope,
+                    user_agent: user_agent.0,
+                    amr
+                };
+                return get_access_token_user_id(c, &grant, &token_secret);
             }).await;
 
             let res_json: String = rocket::serde::json::serde_json::to_string_pretty(&res_access).unwrap();
@@ -644,5 +995,5 @@ async fn auth_with_cookie(conn: UsersDBConnection, cookies: &CookieJar<'_>, inpu
 }
 
 pub fn stage() -> Vec<rocket::Route> {
-    routes![new, authenticate, token_valid, username_taken_endpoint, auth_with_cookie]
+    routes![new, authenticate, token_valid, username_taken_endpoint, auth_with_cookie, session]
 }
\ No newline at end of file
@@ -0,0 +1,224 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac, NewMac};
+use rocket::http::{ContentType, CookieJar, Status};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::serde::json::Json;
+use rocket::serde::json::Value;
+use rocket::serde::json::serde_json::{self, json};
+use rocket::State;
+use rocket_sync_db_pools::postgres;
+use sha3::Sha3_512;
+use urlencoding::encode;
+
+use crate::api::events::record_security_event;
+use crate::api::oauth::OAuthConfig;
+use crate::api::users::get_session_user_id;
+use crate::db::UsersDBConnection;
+use crate::mail::Mailer;
+
+//Duration in seconds (one day)
+const VERIFICATION_LINK_DURATION: u64 = 60*60*24;
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct VerificationRequest{
+    token: Option<String>
+}
+
+fn base64_url(bytes: &[u8]) -> String {
+    return base64::encode_config(bytes, base64::URL_SAFE_NO_PAD);
+}
+
+// The purpose is part of the signed data, so a verification signature can't be passed off as anything else
+fn verification_mac(payload: &str, token_secret: &String) -> Hmac<Sha3_512> {
+    let mut mac = Hmac::<Sha3_512>::new_varkey(token_secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(b"email_verification.");
+    mac.update(payload.as_bytes());
+    return mac;
+}
+
+// Creates a signed token for the user's current email address, it isn't stored anywhere.
+// The token contains the address, so it stops working once the user changes their email.
+pub fn create_verification_token(user_id: &String, email: &String, token_secret: &String) -> String {
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+
+    let payload: String = base64_url(json!({
+        "sub": user_id,
+        "email": email,
+        "exp": since_the_epoch.as_secs() + VERIFICATION_LINK_DURATION
+    }).to_string().as_bytes());
+    let signature: String = base64_url(&verification_mac(&payload, token_secret).finalize().into_bytes());
+
+    return payload + "." + &signature;
+}
+
+// Returns the user id and email address from the token if the signature is valid and it hasn't expired
+fn read_verification_token(token: &str, token_secret: &String) -> Option<(String, String)> {
+    let parts: Vec<&str> = token.split('.').collect();
+    if parts.len() != 2 {
+        return None;
+    }
+
+    let signature: Vec<u8> = match base64::decode_config(parts[1], base64::URL_SAFE_NO_PAD) {
+        Ok(signature) => signature,
+        Err(_e) => return None
+    };
+    if verification_mac(parts[0], token_secret).verify(&signature).is_err() {
+        return None;
+    }
+
+    let claims: Value = base64::decode_config(parts[0], base64::URL_SAFE_NO_PAD).ok().and_then(|payload| serde_json::from_slice(&payload).ok())?;
+
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    if claims["exp"].as_u64().unwrap_or(0) < since_the_epoch.as_secs() {
+        return None;
+    }
+
+    return match (claims["sub"].as_str(), claims["email"].as_str()) {
+        (Some(user_id), Some(email)) => Some((String::from(user_id), String::from(email))),
+        _ => None
+    };
+}
+
+// 0 - verified, 1 - invalid or expired link, 2 - internal error
+pub fn verify_email(conn: &mut postgres::Client, token: &str, token_secret: &String) -> u8 {
+    let (user_id, email) = match read_verification_token(token, token_secret) {
+        Some(verified) => verified,
+        None => return 1
+    };
+
+    let result = conn.execute("UPDATE users SET email_verified = true WHERE id=$1 AND email=$2", &[&user_id, &email]);
+    return match result {
+        Ok(0) => 1,
+        Ok(_rows_updated) => {
+            record_security_event(conn, &user_id, None, "email_verified", "the email address was verified");
+            0
+        },
+        Err(_e) => 2
+    };
+}
+
+// Returns the user's email address if it still has to be verified
+fn get_unverified_email(conn: &mut postgres::Client, user_id: &String) -> Option<String> {
+    return match conn.query_opt("SELECT email FROM users WHERE id=$1 AND email_verified = false", &[user_id]) {
+        Ok(Some(row)) => row.get(0),
+        _ => None
+    };
+}
+
+// Clients can require a verified email address before any tokens are issued to the user
+pub fn meets_email_requirement(conn: &mut postgres::Client, user_id: &String, client_id: &String) -> bool {
+    let result = conn.query_opt("SELECT clients.require_verified_email, users.email_verified FROM clients, users WHERE clients.client_id=$1 AND users.id=$2",
+                                &[client_id, user_id]);
+
+    return match result {
+        Ok(Some(row)) => {
+            let required: bool = row.get(0);
+            let verified: bool = row.get(1);
+            !required || verified
+        },
+        _ => false
+    };
+}
+
+// Mails the user a link to verify their email address, returns false if it is already verified or the mail couldn't be sent
+pub async fn send_verification_email(conn: &UsersDBConnection, mailer: &Mailer, config: &OAuthConfig, user_id: String) -> bool {
+    let user_id_: String = user_id.clone();
+    let email: String = match conn.run(move |c| {
+        return get_unverified_email(c, &user_id_);
+    }).await {
+        Some(email) => email,
+        None => return false
+    };
+
+    return mail_verification_link(mailer, config, &user_id, &email).await;
+}
+
+async fn mail_verification_link(mailer: &Mailer, config: &OAuthConfig, user_id: &String, email: &String) -> bool {
+    let token: String = create_verification_token(user_id, email, &config.token_secret);
+    let link: String = format!("{}/account/verify_email?token={}", config.issuer, encode(&token));
+    let body: String = format!("Please confirm your email address by opening this link:\n\n{}\n\nThe link is valid for 24 hours. If you didn't create an account, you can ignore this email.", link);
+
+    return mailer.send(email, "Confirm your email address", body).await;
+}
+
+#[post("/verify_email", format = "json", data = "<input>")]
+async fn verify(conn: UsersDBConnection, config: &State<OAuthConfig>, input: Json<VerificationRequest>) -> (Status, (ContentType, String)) {
+    let token: String = match input.into_inner().token {
+        Some(token) => token,
+        None => return (Status::BadRequest, (ContentType::JSON, String::from("{\"status_code\": 400, \"error\": \"invalid request\", \"success\": false}")))
+    };
+    let token_secret: String = config.token_secret.clone();
+
+    let res: u8 = conn.run(move |c| {
+        return verify_email(c, &token, &token_secret);
+    }).await;
+
+    return match res {
+        0 => (Status::Ok, (ContentType::JSON, String::from("{\"status_code\": 200, \"error\": null, \"success\": true}"))),
+        1 => (Status::BadRequest, (ContentType::JSON, String::from("{\"status_code\": 400, \"error\": \"invalid or expired link\", \"success\": false}"))),
+        _ => (Status::InternalServerError, (ContentType::JSON, String::from("{\"status_code\": 500, \"error\": \"internal server error\", \"success\": false}")))
+    };
+}
+
+// Sends the logged in user a new link, for when the first one expired or got lost
+#[post("/verification")]
+async fn resend(conn: UsersDBConnection, cookies: &CookieJar<'_>, mailer: &State<Mailer>, config: &State<OAuthConfig>) -> (Status, (ContentType, String)) {
+    let user_id: String = match get_session_user_id(&conn, cookies).await {
+        Some(user_id) => user_id,
+        None => return (Status::Unauthorized, (ContentType::JSON, String::from("{\"status_code\": 401, \"error\": \"not logged in\", \"success\": false}")))
+    };
+
+    if !send_verification_email(&conn, mailer, config, user_id).await {
+        return (Status::Conflict, (ContentType::JSON, String::from("{\"status_code\": 409, \"error\": \"email already verified or could not be sent\", \"success\": false}")));
+    }
+
+    return (Status::Ok, (ContentType::JSON, String::from("{\"status_code\": 200, \"error\": null, \"success\": true}")));
+}
+
+pub fn stage() -> Vec<rocket::Route> {
+    routes![verify, resend]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::api::oauth::OAuthConfig;
+    use crate::mail::Mailer;
+    use crate::mail::memory::MemoryTransport;
+
+    use super::{mail_verification_link, read_verification_token};
+
+    #[rocket::async_test]
+    async fn mailed_link_verifies_the_address() {
+        let config = OAuthConfig{
+            issuer: String::from("https://sso.example.com"),
+            signing_algorithm: String::from("ES256"),
+            token_secret: String::from("secret")
+        };
+        let transport: Arc<MemoryTransport> = Arc::new(MemoryTransport::default());
+        let mailer: Mailer = Mailer::new(String::from("sso@example.com"), transport.clone());
+        let user_id: String = String::from("user");
+        let email: String = String::from("john@example.com");
+
+        assert!(mail_verification_link(&mailer, &config, &user_id, &email).await);
+
+        let sent = transport.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].to, email);
+
+        let prefix: &str = "https://sso.example.com/account/verify_email?token=";
+        let link: &str = sent[0].body.lines().find(|line| line.starts_with(prefix)).unwrap();
+        let token: String = urlencoding::decode(&link[prefix.len()..]).unwrap().into_owned();
+        assert_eq!(read_verification_token(&token, &config.token_secret), Some((user_id, email)));
+        assert_eq!(read_verification_token(&token, &String::from("other secret")), None);
+    }
+}
\ No newline at end of file
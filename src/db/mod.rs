@@ -1,10 +1,35 @@
-use rocket_sync_db_pools::{database, postgres};
+use std::time::Duration;
+
+use rocket::{Build, Rocket};
+use rocket_sync_db_pools::{database, postgres, r2d2, r2d2_postgres, Config};
 
 #[database("users_db")]
 pub struct UsersDBConnection(postgres::Client);
 
+pub type BackgroundPool = r2d2::Pool<r2d2_postgres::PostgresConnectionManager<postgres::NoTls>>;
+
+// Pool for tasks that run outside of requests, the request pool only hands out connections through the running
+// instance. It uses the same users_db settings, but only opens connections when they are needed and closes them
+// again once they are idle.
+pub fn background_pool(rocket: &Rocket<Build>) -> Option<BackgroundPool> {
+    let config: Config = match Config::from("users_db", rocket) {
+        Ok(config) => config,
+        Err(_e) => return None
+    };
+    let url: postgres::Config = match config.url.parse() {
+        Ok(url) => url,
+        Err(_e) => return None
+    };
+
+    return Some(r2d2::Pool::builder()
+        .max_size(config.pool_size)
+        .min_idle(Some(0))
+        .connection_timeout(Duration::from_secs(config.timeout as u64))
+        .build_unchecked(r2d2_postgres::PostgresConnectionManager::new(url, postgres::NoTls)));
+}
+
 pub fn stage() -> rocket::fairing::AdHoc {
     rocket::fairing::AdHoc::on_ignite("Login", |rocket| async {
         rocket.attach(UsersDBConnection::fairing())
     })
-}
\ No newline at end of file
+}
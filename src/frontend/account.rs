@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use rocket_dyn_templates::{Template};
+use rocket::http::CookieJar;
+use rocket::response::Redirect;
+use rocket::serde::json::serde_json::json;
+use rocket::State;
+use crate::api::account::{get_account_settings, AccountSettings};
+use crate::api::apps::{get_connected_apps, ConnectedApp};
+use crate::api::oauth::OAuthConfig;
+use crate::api::users::get_session_user_id;
+use crate::api::verification::verify_email;
+use crate::db::UsersDBConnection;
+
+// Only account pages are allowed after logging in, anything else would make this an open redirect
+fn account_redirect_uri(redirect_uri: Option<String>) -> String {
+    return match redirect_uri {
+        Some(redirect_uri) if redirect_uri.starts_with("/account/") => redirect_uri,
+        _ => String::from("/account/apps")
+    };
+}
+
+#[get("/login?<redirect_uri>")]
+fn login(redirect_uri: Option<String>) -> Template {
+    let mut context: HashMap<&str, String> = HashMap::new();
+    context.insert("redirect_uri", account_redirect_uri(redirect_uri));
+    return Template::render("account_login", context);
+}
+
+#[get("/apps")]
+async fn apps(conn: UsersDBConnection, cookies: &CookieJar<'_>) -> Result<Template, Redirect> {
+    let user_id: String = match get_session_user_id(&conn, cookies).await {
+        Some(user_id) => user_id,
+        None => return Err(Redirect::to("/account/login?redirect_uri=%2Faccount%2Fapps"))
+    };
+
+    let connected_apps: Option<Vec<ConnectedApp>> = conn.run(move |c| {
+        return get_connected_apps(c, &user_id);
+    }).await;
+
+    return match connected_apps {
+        Some(connected_apps) => Ok(Template::render("connected_apps", json!({ "apps": connected_apps }))),
+        None => {
+            let mut context: HashMap<&str, &str> = HashMap::new();
+            context.insert("error", "Could not load your connected apps");
+            Ok(Template::render("error", context))
+        }
+    };
+}
+
+#[get("/settings")]
+async fn settings(conn: UsersDBConnection, cookies: &CookieJar<'_>) -> Result<Template, Redirect> {
+    let user_id: String = match get_session_user_id(&conn, cookies).await {
+        Some(user_id) => user_id,
+        None => return Err(Redirect::to("/account/login?redirect_uri=%2Faccount%2Fsettings"))
+    };
+
+    let settings: Option<AccountSettings> = conn.run(move |c| {
+        return get_account_settings(c, &user_id);
+    }).await;
+
+    return match settings {
+        Some(settings) => Ok(Template::render("account_settings", settings)),
+        None => {
+            let mut context: HashMap<&str, &str> = HashMap::new();
+            context.insert("error", "Could not load your account settings");
+            Ok(Template::render("error", context))
+        }
+    };
+}
+
+// Opened from the link in the verification email
+#[get("/verify_email?<token>")]
+async fn verify_email_page(conn: UsersDBConnection, config: &State<OAuthConfig>, token: String) -> Template {
+    let token_secret: String = config.token_secret.clone();
+    let res: u8 = conn.run(move |c| {
+        return verify_email(c, &token, &token_secret);
+    }).await;
+
+    let message: &str = match res {
+        0 => "Your email address has been verified.",
+        1 => "This link is invalid or has expired. Log in and request a new one.",
+        _ => "Your email address could not be verified, please try again later."
+    };
+
+    let mut context: HashMap<&str, &str> = HashMap::new();
+    context.insert("message", message);
+    return Template::render("email_verification", context);
+}
+
+#[get("/forgot_password")]
+fn forgot_password() -> Template {
+    let context: HashMap<&str, &str> = HashMap::new();
+    return Template::render("forgot_password", context);
+}
+
+// Opened from the link in the password reset email, the token is only checked once the new password is sent
+#[get("/reset_password?<token>")]
+fn reset_password(token: String) -> Template {
+    let mut context: HashMap<&str, String> = HashMap::new();
+    context.insert("token", token);
+    return Template::render("reset_password", context);
+}
+
+pub fn stage() -> Vec<rocket::Route> {
+    routes![login, apps, settings, verify_email_page, forgot_password, reset_password]
+}
\ No newline at end of file
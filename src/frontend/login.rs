@@ -1,37 +1,65 @@
 use std::collections::HashMap;
 use rocket_dyn_templates::{Template};
 use rocket::serde::json::serde_json::json;
-use crate::api::clients::{get_client_info, ClientInfo, scope_to_vec};
+use crate::api::clients::{get_client_info, is_redirect_uri_registered, ClientInfo};
+use crate::api::scopes::{get_scope_details, parse_scope, Scope};
+use crate::api::oauth::{error_redirect, validate_client_redirect};
 use crate::api::users::{get_username_by_id, is_user_session_authenticated};
 use crate::db::UsersDBConnection;
 use rocket::http::{CookieJar, Cookie};
+use rocket::response::Redirect;
 use rocket_dyn_templates::handlebars::JsonValue;
 
-#[get("/?<client_id>&<scope>&<redirect_uri>&<forget>", rank = 1)]
-async fn login(conn: UsersDBConnection, cookies: &CookieJar<'_>, client_id: String, scope: u64, redirect_uri: String, forget: Option<bool>) -> Template{
-    let client_info: ClientInfo = conn.run(move |c| {
-        return get_client_info(c, &client_id);
+// Authorization request passed on by /authorize, sent back with the login so the code can be issued
+#[derive(FromForm)]
+struct LoginQuery{
+    client_id: String,
+    scope: String,
+    redirect_uri: String,
+    forget: Option<bool>,
+    code_challenge: Option<String>,
+    code_challenge_method: Option<String>,
+    state: Option<String>,
+    nonce: Option<String>
+}
+
+#[get("/?<query..>", rank = 1)]
+async fn login(conn: UsersDBConnection, cookies: &CookieJar<'_>, query: LoginQuery) -> Result<Template, Redirect>{
+    let LoginQuery{client_id, scope, redirect_uri, forget, code_challenge, code_challenge_method, state, nonce} = query;
+    let redirect_uri_: String = redirect_uri.clone();
+    let (client_info, redirect_uri_registered, scope_details): (ClientInfo, bool, Option<(u64, Vec<Scope>)>) = conn.run(move |c| {
+        let scope_details: Option<(u64, Vec<Scope>)> = parse_scope(c, &scope)
+            .and_then(|scope| get_scope_details(c, scope).map(|details| (scope, details)));
+        return (get_client_info(c, &client_id), is_redirect_uri_registered(c, &client_id, &redirect_uri_), scope_details);
     }).await;
 
     let forget_user: bool = forget.unwrap_or(false);
 
+    // The login page sends the user to the redirect uri, so unknown uris never get that far
+    if client_info.success && !redirect_uri_registered {
+        let mut context: HashMap<&str, &str> = HashMap::new();
+        context.insert("error", "Invalid redirect uri");
+        return Ok(Template::render("error", context));
+    }
+
     if client_info.success {
-        let scope_vec = scope_to_vec(scope);
-        if scope_vec.is_none() {
-            let mut context: HashMap<&str, &str> = HashMap::new();
-            context.insert("error", "Invalid scope");
-            return Template::render("error", context);
+        if scope_details.is_none() {
+            return Err(error_redirect(&redirect_uri, "invalid_scope", "The requested scope is invalid", &state));
         }
         let client_name: String = client_info.client_name.unwrap();
         let client_id_: String = client_info.client_id.unwrap();
-        let scopes: Vec<String> = scope_vec.unwrap();
+        let (scope, scopes): (u64, Vec<Scope>) = scope_details.unwrap();
 
         let mut json_data = json!({
             "client_id": client_id_,
             "scope": scopes,
             "scope_num": scope,
             "redirect_uri": redirect_uri,
-            "client_name": client_name
+            "client_name": client_name,
+            "code_challenge": code_challenge,
+            "code_challenge_method": code_challenge_method,
+            "state": state,
+            "nonce": nonce
         });
 
         if !forget_user {
@@ -39,11 +67,11 @@ async fn login(conn: UsersDBConnection, cookies: &CookieJar<'_>, client_id: Stri
             let series_id_cookie = cookies.get_private("series_id");
             let token_cookie = cookies.get_private("token");
             
-            if series_id_cookie.is_some() && token_cookie.is_some() && user_id_cookie.is_some() {
-                let series_id: String = String::from(series_id_cookie.unwrap().value());
-                let token: String = String::from(token_cookie.unwrap().value());
+            if let (Some(series_id_cookie), Some(token_cookie), Some(user_id_cookie)) = (series_id_cookie, token_cookie, user_id_cookie) {
+                let series_id: String = String::from(series_id_cookie.value());
+                let token: String = String::from(token_cookie.value());
                 // TODO: find a better way to do this
-                let user_id: String = String::from(user_id_cookie.unwrap().value());
+                let user_id: String = String::from(user_id_cookie.value());
                 let user_id__: String = user_id.clone();
                 
                 let res = conn.run(move |c| {
@@ -57,7 +85,7 @@ async fn login(conn: UsersDBConnection, cookies: &CookieJar<'_>, client_id: Stri
 
                     if !username.is_empty() && username!="err" {
                         json_data["username"] = JsonValue::String(username);
-                        return Template::render("logged_in", json_data);
+                        return Ok(Template::render("logged_in", json_data));
                     }
                 }
             }
@@ -67,19 +95,24 @@ async fn login(conn: UsersDBConnection, cookies: &CookieJar<'_>, client_id: Stri
             cookies.remove_private(Cookie::named("token"));
         }
 
-        return Template::render("login", json_data);
+        return Ok(Template::render("login", json_data));
     } else {
         let mut context: HashMap<&str, &str> = HashMap::new();
         context.insert("error", "Invalid client id");
-        return Template::render("error", context);
+        return Ok(Template::render("error", context));
     }
 }
 
-#[get("/?<client_id>&<scope>&<redirect_uri>", rank = 2)]
-async fn login_invalid_scope(client_id: String, scope: String, redirect_uri: String) -> Template{
-    let mut context: HashMap<&str, &str> = HashMap::new();
-    context.insert("error", "Invalid scope");
-    return Template::render("error", context);
+// Missing scopes are sent back to the client once the client and redirect uri are known to be valid
+#[get("/?<client_id>&<redirect_uri>&<state>", rank = 2)]
+async fn login_invalid_scope(conn: UsersDBConnection, client_id: String, redirect_uri: String, state: Option<String>) -> Result<Template, Redirect>{
+    if let Some(error) = validate_client_redirect(&conn, &client_id, &redirect_uri).await {
+        let mut context: HashMap<&str, &str> = HashMap::new();
+        context.insert("error", error);
+        return Ok(Template::render("error", context));
+    }
+
+    return Err(error_redirect(&redirect_uri, "invalid_scope", "The requested scope is invalid", &state));
 }
 
 pub fn stage() -> Vec<rocket::Route> {
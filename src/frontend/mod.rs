@@ -1,5 +1,6 @@
 mod login;
 mod register;
+mod account;
 
 use rocket_dyn_templates::{Template};
 
@@ -8,5 +9,6 @@ pub fn stage() -> rocket::fairing::AdHoc {
         rocket.attach(Template::fairing())
             .mount("/login", login::stage())
             .mount("/register", register::stage())
+            .mount("/account", account::stage())
     });
 }
\ No newline at end of file
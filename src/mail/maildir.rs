@@ -0,0 +1,49 @@
+use std::fs;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rand::Rng;
+
+use super::{build_message, Mail, MailTransport};
+
+// Delivers mail into a local Maildir, so mail can be read with any mail client without a mail server
+pub struct MaildirTransport{
+    path: PathBuf
+}
+
+impl MaildirTransport {
+    pub fn new(path: String) -> Option<MaildirTransport> {
+        let path: PathBuf = PathBuf::from(path);
+        for dir in ["tmp", "new", "cur"] {
+            if fs::create_dir_all(path.join(dir)).is_err() {
+                return None;
+            }
+        }
+
+        return Some(MaildirTransport{
+            path
+        });
+    }
+}
+
+impl MailTransport for MaildirTransport {
+    fn send(&self, mail: &Mail) -> bool {
+        let message = match build_message(mail) {
+            Some(message) => message,
+            None => return false
+        };
+
+        let start = SystemTime::now();
+        let since_the_epoch = start
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards");
+        let file_name: String = format!("{}.{:016x}.sso", since_the_epoch.as_secs(), rand::thread_rng().gen::<u64>());
+
+        // Mail is written to tmp first and then moved to new, so readers never see a partially written file
+        let tmp_path: PathBuf = self.path.join("tmp").join(&file_name);
+        if fs::write(&tmp_path, message.formatted()).is_err() {
+            return false;
+        }
+        return fs::rename(&tmp_path, self.path.join("new").join(&file_name)).is_ok();
+    }
+}
\ No newline at end of file
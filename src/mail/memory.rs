@@ -0,0 +1,32 @@
+use std::sync::Mutex;
+
+use super::{Mail, MailTransport};
+
+// Keeps mail in memory for tests, which read it back with sent()
+#[derive(Default)]
+pub struct MemoryTransport{
+    sent: Mutex<Vec<Mail>>
+}
+
+impl MemoryTransport {
+    // Every mail sent so far, oldest first
+    #[cfg(test)]
+    pub fn sent(&self) -> Vec<Mail> {
+        return match self.sent.lock() {
+            Ok(sent) => sent.clone(),
+            Err(_e) => Vec::new()
+        };
+    }
+}
+
+impl MailTransport for MemoryTransport {
+    fn send(&self, mail: &Mail) -> bool {
+        return match self.sent.lock() {
+            Ok(mut sent) => {
+                sent.push(mail.clone());
+                true
+            },
+            Err(_e) => false
+        };
+    }
+}
\ No newline at end of file
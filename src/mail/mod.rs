@@ -0,0 +1,139 @@
+mod smtp;
+mod maildir;
+pub mod memory;
+
+use std::sync::Arc;
+
+use lettre::Message;
+use lettre::message::header::ContentType;
+use rocket::serde::Deserialize;
+
+use smtp::SmtpMailTransport;
+use maildir::MaildirTransport;
+use memory::MemoryTransport;
+
+#[derive(Deserialize, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct MailConfig{
+    pub mail_transport: String,
+    pub mail_from: String,
+    pub maildir_path: Option<String>,
+    pub smtp_host: Option<String>,
+    pub smtp_port: Option<u16>,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>
+}
+
+#[derive(Clone)]
+pub struct Mail{
+    pub from: String,
+    pub to: String,
+    pub subject: String,
+    pub body: String
+}
+
+// Delivers mail, sending blocks so transports are only called from blocking tasks
+pub trait MailTransport: Send + Sync {
+    fn send(&self, mail: &Mail) -> bool;
+}
+
+// The configured transport, managed by Rocket so handlers can send mail
+pub struct Mailer{
+    from: String,
+    transport: Arc<dyn MailTransport>
+}
+
+impl Mailer {
+    pub fn new(from: String, transport: Arc<dyn MailTransport>) -> Mailer {
+        return Mailer{
+            from,
+            transport
+        };
+    }
+
+    fn mail(&self, to: &str, subject: &str, body: String) -> Mail {
+        return Mail{
+            from: self.from.clone(),
+            to: String::from(to),
+            subject: String::from(subject),
+            body
+        };
+    }
+
+    // Sends a plain text mail, returns false if the transport couldn't deliver it
+    pub async fn send(&self, to: &str, subject: &str, body: String) -> bool {
+        let transport: Arc<dyn MailTransport> = self.transport.clone();
+        let mail: Mail = self.mail(to, subject, body);
+
+        return rocket::tokio::task::spawn_blocking(move || {
+            return transport.send(&mail);
+        }).await.unwrap_or(false);
+    }
+
+    // Sends a plain text mail without waiting for it, so the response takes just as long whether a mail is sent or not
+    pub fn send_in_background(&self, to: &str, subject: &str, body: String) {
+        let transport: Arc<dyn MailTransport> = self.transport.clone();
+        let mail: Mail = self.mail(to, subject, body);
+
+        rocket::tokio::task::spawn_blocking(move || {
+            if !transport.send(&mail) {
+                println!("Failed to send mail \"{}\"", mail.subject);
+            }
+        });
+    }
+}
+
+// Builds the RFC 5322 message for transports that deliver the whole message, None if an address is invalid
+fn build_message(mail: &Mail) -> Option<Message> {
+    let from = match mail.from.parse() {
+        Ok(from) => from,
+        Err(_e) => return None
+    };
+    let to = match mail.to.parse() {
+        Ok(to) => to,
+        Err(_e) => return None
+    };
+
+    return Message::builder()
+        .from(from)
+        .to(to)
+        .subject(mail.subject.clone())
+        .header(ContentType::TEXT_PLAIN)
+        .body(mail.body.clone())
+        .ok();
+}
+
+fn create_transport(config: &MailConfig) -> Option<Arc<dyn MailTransport>> {
+    return match config.mail_transport.as_str() {
+        "smtp" => {
+            let host: &String = config.smtp_host.as_ref()?;
+            SmtpMailTransport::new(host, config.smtp_port.unwrap_or(587), config.smtp_username.clone(), config.smtp_password.clone())
+                .map(|transport| Arc::new(transport) as Arc<dyn MailTransport>)
+        },
+        "maildir" => MaildirTransport::new(config.maildir_path.clone().unwrap_or_else(|| String::from("mail")))
+            .map(|transport| Arc::new(transport) as Arc<dyn MailTransport>),
+        // Mail that is only kept in memory never arrives, a release build refuses to lose it
+        "memory" if cfg!(debug_assertions) => Some(Arc::new(MemoryTransport::default())),
+        _ => None
+    };
+}
+
+pub fn stage() -> rocket::fairing::AdHoc {
+    return rocket::fairing::AdHoc::try_on_ignite("Mail", |rocket| async {
+        let config: MailConfig = match rocket.figment().extract::<MailConfig>() {
+            Ok(config) => config,
+            Err(e) => {
+                println!("Invalid mail configuration: {}", e);
+                return Err(rocket);
+            }
+        };
+
+        return match create_transport(&config) {
+            Some(transport) => Ok(rocket.manage(Mailer::new(config.mail_from, transport))),
+            None => {
+                println!("Could not set up the {} mail transport", config.mail_transport);
+                Err(rocket)
+            }
+        };
+    });
+}
\ No newline at end of file
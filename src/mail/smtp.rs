@@ -0,0 +1,47 @@
+use lettre::{SmtpTransport, Transport};
+use lettre::transport::smtp::authentication::Credentials;
+
+use super::{build_message, Mail, MailTransport};
+
+// Delivers mail to an SMTP server, the connection is upgraded with STARTTLS before logging in
+pub struct SmtpMailTransport{
+    transport: SmtpTransport
+}
+
+impl SmtpMailTransport {
+    pub fn new(host: &str, port: u16, username: Option<String>, password: Option<String>) -> Option<SmtpMailTransport> {
+        let mut builder = match SmtpTransport::starttls_relay(host) {
+            Ok(builder) => builder.port(port),
+            Err(_e) => return None
+        };
+
+        if let (Some(username), Some(password)) = (username, password) {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+
+        return Some(SmtpMailTransport{
+            transport: builder.build()
+        });
+    }
+}
+
+impl MailTransport for SmtpMailTransport {
+    fn send(&self, mail: &Mail) -> bool {
+        let message = match build_message(mail) {
+            Some(message) => message,
+            None => return false
+        };
+
+        return match self.transport.send(&message) {
+            Ok(_response) => true,
+            // The server's reply can quote the recipient, so only its status code is printed
+            Err(e) => {
+                match e.status() {
+                    Some(code) => println!("SMTP server rejected mail \"{}\" with {}", mail.subject, code),
+                    None => println!("Could not reach the SMTP server to send mail \"{}\"", mail.subject)
+                }
+                false
+            }
+        };
+    }
+}
\ No newline at end of file
@@ -6,6 +6,7 @@ use rocket::fs::FileServer;
 mod api;
 mod frontend;
 mod db;
+mod mail;
 
 #[launch]
 fn rocket() -> _ {
@@ -13,5 +14,6 @@ fn rocket() -> _ {
         .attach(api::stage())
         .attach(frontend::stage())
         .attach(db::stage())
+        .attach(mail::stage())
         .mount("/static", FileServer::from("static/"))
 }